@@ -23,12 +23,39 @@ pub enum Error {
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// A level-triggered legacy PCI IRQ: the `trigger` eventfd the device writes to assert the line,
+/// paired with a `resample` eventfd the kernel signals once the guest sends EOI. A device that
+/// still has interrupt status pending when it's resampled must write `trigger` again, or the
+/// line stays deasserted and the guest never sees the remaining work.
+pub struct IrqLevelEvent {
+    pub trigger: EventFd,
+    pub resample: EventFd,
+}
+
+/// Describes a guest rewrite of a BAR's base address, decoded from a config space write to that
+/// BAR's address register. `len` is the BAR's size, which a base address rewrite never changes,
+/// so the device manager can use it to move an already-registered MMIO range and ioeventfds to
+/// `new_base` without having to look the size up again.
+#[derive(Debug, Clone, Copy)]
+pub struct BarReprogrammingParams {
+    pub old_base: u64,
+    pub new_base: u64,
+    pub len: u64,
+}
+
+// Standard PCI type 0 header: BAR0..BAR5 occupy config space dwords 4 through 9.
+const PCI_BAR0_REG: usize = 4;
+const PCI_NUM_BARS: usize = 6;
+// The low 4 bits of a memory BAR's address register are flag bits (prefetchable, type), not
+// part of the base address.
+const PCI_BAR_ADDR_MASK: u32 = !0xf;
+
 pub trait PciDevice: Send {
     /// A vector of device-specific file descriptors that must be kept open
     /// after jailing. Must be called before the process is jailed.
     fn keep_fds(&self) -> Vec<RawFd>;
-    /// Assign a legacy PCI IRQ to this device.
-    fn assign_irq(&mut self, _irq_evt: EventFd, _irq_num: u32, _irq_pin: PciInterruptPin) {}
+    /// Assign a legacy, level-triggered PCI IRQ to this device.
+    fn assign_irq(&mut self, _irq_evt: IrqLevelEvent, _irq_num: u32, _irq_pin: PciInterruptPin) {}
     /// Gives the device guest memory if it is needed.
     fn set_guest_memory(&mut self, mem: GuestMemory) {}
     /// Allocates the needed IO BAR space using the `allocate` function which takes a size and
@@ -56,6 +83,41 @@ pub trait PciDevice: Send {
     /// * `addr` - The guest address inside the BAR.
     /// * `data` - The data to write.
     fn write_bar(&mut self, addr: u64, data: &[u8]);
+    /// Gets the size of BAR `bar_num`, if this device has one allocated there. Used to decode a
+    /// BAR base address rewrite into a `BarReprogrammingParams`.
+    fn bar_size(&self, _bar_num: usize) -> Option<u64> {
+        None
+    }
+    /// Checks whether a config space write at `reg_idx` changed a BAR's base address, given the
+    /// register's raw value before and after the write.
+    fn detect_bar_reprogram(
+        &self,
+        reg_idx: usize,
+        old_reg_value: u32,
+        new_reg_value: u32,
+    ) -> Option<BarReprogrammingParams> {
+        if reg_idx < PCI_BAR0_REG || reg_idx >= PCI_BAR0_REG + PCI_NUM_BARS {
+            return None;
+        }
+        let old_base = (old_reg_value & PCI_BAR_ADDR_MASK) as u64;
+        let new_base = (new_reg_value & PCI_BAR_ADDR_MASK) as u64;
+        if old_base == new_base {
+            return None;
+        }
+        let len = self.bar_size(reg_idx - PCI_BAR0_REG)?;
+        Some(BarReprogrammingParams {
+            old_base,
+            new_base,
+            len,
+        })
+    }
+    /// Updates this device's own bookkeeping after a BAR it owns has moved from `old_base` to
+    /// `new_base` in guest physical address space. Does not itself move the device's ioeventfds
+    /// or re-register it on the MMIO bus - that's the device manager's job once it observes a
+    /// `BarReprogrammingParams` from `detect_bar_reprogram`.
+    fn move_bar(&mut self, _old_base: u64, _new_base: u64) -> Result<()> {
+        Ok(())
+    }
 }
 
 impl<T: PciDevice> BusDevice for T {
@@ -72,6 +134,8 @@ impl<T: PciDevice> BusDevice for T {
             return;
         }
 
+        let old_reg_value = self.config_registers().read_reg(reg_idx);
+
         let regs = self.config_registers_mut();
 
         match data.len() {
@@ -83,6 +147,14 @@ impl<T: PciDevice> BusDevice for T {
             4 => regs.write_reg(reg_idx, LittleEndian::read_u32(data)),
             _ => (),
         }
+
+        let new_reg_value = self.config_registers().read_reg(reg_idx);
+        if let Some(params) = self.detect_bar_reprogram(reg_idx, old_reg_value, new_reg_value) {
+            // This only updates the device's own view of where its BAR lives; actually moving the
+            // ioeventfds and the MMIO bus registration to `params.new_base` is the device
+            // manager's job, and that loop isn't part of this source tree.
+            let _ = self.move_bar(params.old_base, params.new_base);
+        }
     }
 
     fn config_register_read(&self, reg_idx: usize) -> u32 {
@@ -94,7 +166,7 @@ impl<T: PciDevice + ?Sized> PciDevice for Box<T> {
     fn keep_fds(&self) -> Vec<RawFd> {
         (**self).keep_fds()
     }
-    fn assign_irq(&mut self, irq_evt: EventFd, irq_num: u32, irq_pin: PciInterruptPin) {
+    fn assign_irq(&mut self, irq_evt: IrqLevelEvent, irq_num: u32, irq_pin: PciInterruptPin) {
      (**self).assign_irq(irq_evt, irq_num, irq_pin)
     }
     /// Gives the device guest memory if it is needed.
@@ -134,4 +206,96 @@ impl<T: PciDevice + ?Sized> PciDevice for Box<T> {
     fn write_bar(&mut self, addr: u64, data: &[u8]) {
         (**self).write_bar(addr, data)
     }
+    fn bar_size(&self, bar_num: usize) -> Option<u64> {
+        (**self).bar_size(bar_num)
+    }
+    fn detect_bar_reprogram(
+        &self,
+        reg_idx: usize,
+        old_reg_value: u32,
+        new_reg_value: u32,
+    ) -> Option<BarReprogrammingParams> {
+        (**self).detect_bar_reprogram(reg_idx, old_reg_value, new_reg_value)
+    }
+    fn move_bar(&mut self, old_base: u64, new_base: u64) -> Result<()> {
+        (**self).move_bar(old_base, new_base)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A minimal `PciDevice` with a controllable `bar_size`, just enough to exercise
+    // `detect_bar_reprogram`'s default implementation. `config_registers`/`config_registers_mut`
+    // are never called by these tests, so they're left unimplemented rather than constructing a
+    // real `PciConfiguration` (an opaque type from outside this tree).
+    struct FakePciDevice {
+        bar_sizes: [Option<u64>; PCI_NUM_BARS],
+    }
+
+    impl FakePciDevice {
+        fn with_bar0_size(size: u64) -> FakePciDevice {
+            let mut bar_sizes = [None; PCI_NUM_BARS];
+            bar_sizes[0] = Some(size);
+            FakePciDevice { bar_sizes }
+        }
+    }
+
+    impl PciDevice for FakePciDevice {
+        fn keep_fds(&self) -> Vec<RawFd> {
+            Vec::new()
+        }
+        fn config_registers(&self) -> &PciConfiguration {
+            unimplemented!("not exercised by these tests")
+        }
+        fn config_registers_mut(&mut self) -> &mut PciConfiguration {
+            unimplemented!("not exercised by these tests")
+        }
+        fn read_bar(&mut self, _addr: u64, _data: &mut [u8]) {}
+        fn write_bar(&mut self, _addr: u64, _data: &[u8]) {}
+        fn bar_size(&self, bar_num: usize) -> Option<u64> {
+            self.bar_sizes.get(bar_num).cloned().unwrap_or(None)
+        }
+    }
+
+    #[test]
+    fn detect_bar_reprogram_finds_a_real_base_address_rewrite() {
+        let device = FakePciDevice::with_bar0_size(0x1000);
+        let old_reg_value = 0x1000_0000u32;
+        let new_reg_value = 0x2000_0000u32;
+
+        let params = device
+            .detect_bar_reprogram(PCI_BAR0_REG, old_reg_value, new_reg_value)
+            .expect("a changed base address must be detected");
+        assert_eq!(params.old_base, old_reg_value as u64);
+        assert_eq!(params.new_base, new_reg_value as u64);
+        assert_eq!(params.len, 0x1000);
+    }
+
+    #[test]
+    fn detect_bar_reprogram_ignores_a_flag_bit_only_write() {
+        let device = FakePciDevice::with_bar0_size(0x1000);
+        // Same masked base address; only the low "prefetchable" flag bit changes.
+        let old_reg_value = 0x1000_0000u32;
+        let new_reg_value = 0x1000_0001u32;
+
+        assert!(device
+            .detect_bar_reprogram(PCI_BAR0_REG, old_reg_value, new_reg_value)
+            .is_none());
+    }
+
+    #[test]
+    fn detect_bar_reprogram_ignores_registers_outside_bar0_to_bar5() {
+        let device = FakePciDevice::with_bar0_size(0x1000);
+        let old_reg_value = 0x1000_0000u32;
+        let new_reg_value = 0x2000_0000u32;
+
+        assert!(device
+            .detect_bar_reprogram(PCI_BAR0_REG - 1, old_reg_value, new_reg_value)
+            .is_none());
+        assert!(device
+            .detect_bar_reprogram(PCI_BAR0_REG + PCI_NUM_BARS, old_reg_value, new_reg_value)
+            .is_none());
+    }
 }
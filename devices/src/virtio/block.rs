@@ -3,12 +3,17 @@
 // found in the LICENSE file.
 
 use std::cmp;
+use std::collections::HashSet;
+use std::fs::{self, File};
 use std::io::{self, Seek, SeekFrom, Read, Write};
-use std::mem::{size_of, size_of_val};
+use std::iter;
+use std::mem::{self, size_of, size_of_val};
+use std::num::Wrapping;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::result;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::time::Duration;
 use std::u32;
@@ -22,7 +27,7 @@ use sys_util::{
 
 use data_model::{DataInit, Le16, Le32, Le64};
 
-use super::{VirtioDevice, Queue, DescriptorChain, INTERRUPT_STATUS_USED_RING, TYPE_BLOCK};
+use super::{VirtioDevice, VirtioInterrupt, Queue, DescriptorChain, TYPE_BLOCK};
 
 const QUEUE_SIZE: u16 = 256;
 const QUEUE_SIZES: &'static [u16] = &[QUEUE_SIZE];
@@ -37,18 +42,29 @@ const DISCARD_SECTOR_ALIGNMENT: u32 = 128;
 const VIRTIO_BLK_T_IN: u32 = 0;
 const VIRTIO_BLK_T_OUT: u32 = 1;
 const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
 const VIRTIO_BLK_T_DISCARD: u32 = 11;
 const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
 
+// Size in bytes of the device-id string returned by VIRTIO_BLK_T_GET_ID.
+const VIRTIO_BLK_ID_BYTES: u32 = 20;
+
+// Version tag for the blob produced by `Block::snapshot` / consumed by `Block::restore`. Bump
+// this whenever the layout below changes so restore can refuse blobs it doesn't understand.
+const VIRTIO_BLK_STATE_VERSION: u32 = 2;
+
 const VIRTIO_BLK_S_OK: u8 = 0;
 const VIRTIO_BLK_S_IOERR: u8 = 1;
 const VIRTIO_BLK_S_UNSUPP: u8 = 2;
 
 const VIRTIO_BLK_F_RO: u32 = 5;
 const VIRTIO_BLK_F_FLUSH: u32 = 9;
+const VIRTIO_BLK_F_MQ: u32 = 12;
 const VIRTIO_BLK_F_DISCARD: u32 = 13;
 const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 14;
 
+const VIRTIO_RING_F_EVENT_IDX: u32 = 29;
+
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
 struct virtio_blk_geometry {
@@ -83,6 +99,8 @@ struct virtio_blk_config {
     topology: virtio_blk_topology,
     writeback: u8,
     unused0: [u8; 3],
+    num_queues: Le16,
+    unused2: [u8; 2],
     max_discard_sectors: Le32,
     max_discard_seg: Le32,
     discard_sector_alignment: Le32,
@@ -108,14 +126,112 @@ const VIRTIO_BLK_DISCARD_WRITE_ZEROES_FLAG_UNMAP: u32 = 1 << 0;
 // Safe because it only has data and has no implicit padding.
 unsafe impl DataInit for virtio_blk_discard_write_zeroes {}
 
-pub trait DiskFile: Read + Seek + Write + WriteZeroes {}
-impl<D: Read + Seek + Write + WriteZeroes> DiskFile for D {}
+/// A backend for the virtio block device's contents, abstracted over byte offset rather than a
+/// specific file type so a RAM disk, an overlay image, or the synthesized FAT backend can all sit
+/// behind the same virtio-blk front end. `Block` only ever talks to its disk image through this
+/// trait.
+pub trait DiskImage: Send {
+    /// Total size of the backend, in bytes.
+    fn len(&self) -> u64;
+    /// Reads into `buf` starting at `offset`, returning the number of bytes actually read.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+    /// Writes `buf` at `offset`, returning the number of bytes actually written.
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize>;
+    /// Flushes any writes buffered by the backend to stable storage.
+    fn flush(&mut self) -> io::Result<()>;
+    /// Discards `length` bytes starting at `offset`, if the backend supports it; a no-op
+    /// otherwise.
+    fn discard(&mut self, _offset: u64, _length: u64) -> io::Result<()> {
+        Ok(())
+    }
+    /// Writes `length` zero bytes starting at `offset`.
+    fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<()>;
+    /// True if `discard` is more than a no-op for this backend; gates whether `Block` advertises
+    /// VIRTIO_BLK_F_DISCARD to the guest.
+    fn supports_discard(&self) -> bool {
+        false
+    }
+    /// True if `write_zeroes_at` is implemented; gates VIRTIO_BLK_F_WRITE_ZEROES.
+    fn supports_write_zeroes(&self) -> bool {
+        true
+    }
+    /// The fd to keep open across a jail boundary, for backends that are fd-based.
+    fn as_raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+impl DiskImage for File {
+    fn len(&self) -> u64 {
+        self.metadata().map(|m| m.len()).unwrap_or(0)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.seek(SeekFrom::Start(offset))?;
+        Read::read(self, buf)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        self.seek(SeekFrom::Start(offset))?;
+        Write::write(self, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Write::flush(self)
+    }
+
+    fn discard(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        self.write_zeroes_at(offset, length as usize)
+    }
+
+    fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        WriteZeroes::write_zeroes(self, length)
+    }
+
+    fn supports_discard(&self) -> bool {
+        true
+    }
+
+    fn as_raw_fd(&self) -> Option<RawFd> {
+        Some(AsRawFd::as_raw_fd(self))
+    }
+}
+
+// Adapts a `DiskImage`'s offset-based `read_at`/`write_at` into the `Read`/`Write` traits that
+// `GuestMemory::read_to_memory`/`write_from_memory` expect, tracking its own cursor rather than
+// relying on the backend to remember a seek position across calls.
+struct DiskImageCursor<'a, T: 'a + DiskImage + ?Sized> {
+    disk: &'a mut T,
+    position: u64,
+}
+
+impl<'a, T: 'a + DiskImage + ?Sized> Read for DiskImageCursor<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.disk.read_at(self.position, buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<'a, T: 'a + DiskImage + ?Sized> Write for DiskImageCursor<'a, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.disk.write_at(self.position, buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.disk.flush()
+    }
+}
 
 #[derive(PartialEq)]
 enum RequestType {
     In,
     Out,
     Flush,
+    GetDeviceID,
     Discard,
     WriteZeroes,
     Unsupported(u32),
@@ -146,6 +262,7 @@ fn request_type(mem: &GuestMemory,
         VIRTIO_BLK_T_IN => Ok(RequestType::In),
         VIRTIO_BLK_T_OUT => Ok(RequestType::Out),
         VIRTIO_BLK_T_FLUSH => Ok(RequestType::Flush),
+        VIRTIO_BLK_T_GET_ID => Ok(RequestType::GetDeviceID),
         VIRTIO_BLK_T_DISCARD => Ok(RequestType::Discard),
         VIRTIO_BLK_T_WRITE_ZEROES => Ok(RequestType::WriteZeroes),
         t => Ok(RequestType::Unsupported(t)),
@@ -182,10 +299,6 @@ enum ExecuteError {
         sector: u64,
         guestmemerr: GuestMemoryError
     },
-    Seek {
-        ioerr: io::Error,
-        sector: u64
-    },
     TimerFd(SysError),
     Write {
         addr: GuestAddress,
@@ -208,7 +321,6 @@ impl ExecuteError {
             &ExecuteError::ArmingTimer(_) => VIRTIO_BLK_S_IOERR,
             &ExecuteError::Flush(_) => VIRTIO_BLK_S_IOERR,
             &ExecuteError::Read{ .. } => VIRTIO_BLK_S_IOERR,
-            &ExecuteError::Seek{ .. } => VIRTIO_BLK_S_IOERR,
             &ExecuteError::TimerFd(_) => VIRTIO_BLK_S_IOERR,
             &ExecuteError::Write{ .. } => VIRTIO_BLK_S_IOERR,
             &ExecuteError::DiscardWriteZeroes{ .. } => VIRTIO_BLK_S_IOERR,
@@ -220,8 +332,7 @@ impl ExecuteError {
 struct Request {
     request_type: RequestType,
     sector: u64,
-    data_addr: GuestAddress,
-    data_len: u32,
+    data: Vec<(GuestAddress, u32)>,
     status_addr: GuestAddress,
     discard_write_zeroes_seg: Option<virtio_blk_discard_write_zeroes>,
 }
@@ -240,6 +351,8 @@ impl Request {
             Request::parse_flush(avail_desc, mem)
         } else if req_type == RequestType::Discard || req_type == RequestType::WriteZeroes {
             Request::parse_discard_write_zeroes(avail_desc, mem, req_type)
+        } else if req_type == RequestType::GetDeviceID {
+            Request::parse_get_device_id(avail_desc, mem)
         } else {
             Request::parse_read_write(avail_desc, mem, req_type)
         }
@@ -266,8 +379,7 @@ impl Request {
         Ok(Request {
                request_type: RequestType::Flush,
                sector: sector,
-               data_addr: GuestAddress(0),
-               data_len: 0,
+               data: Vec::new(),
                status_addr: status_desc.addr,
                discard_write_zeroes_seg: None,
            })
@@ -310,19 +422,16 @@ impl Request {
         Ok(Request {
             request_type: req_type,
             sector: 0,
-            data_addr: GuestAddress(0),
-            data_len: 0,
+            data: Vec::new(),
             status_addr: status_desc.addr,
             discard_write_zeroes_seg: Some(seg),
         })
     }
 
-    fn parse_read_write(avail_desc: &DescriptorChain,
-                        mem: &GuestMemory,
-                        req_type: RequestType)
+    fn parse_get_device_id(avail_desc: &DescriptorChain,
+                           _mem: &GuestMemory)
         -> result::Result<Request, ParseError>
     {
-        let sector = sector(&mem, avail_desc.addr)?;
         let data_desc = avail_desc
             .next_descriptor()
             .ok_or(ParseError::DescriptorChainTooShort)?;
@@ -330,12 +439,12 @@ impl Request {
             .next_descriptor()
             .ok_or(ParseError::DescriptorChainTooShort)?;
 
-        if data_desc.is_write_only() && req_type == RequestType::Out {
-            return Err(ParseError::UnexpectedWriteOnlyDescriptor);
+        if !data_desc.is_write_only() {
+            return Err(ParseError::UnexpectedReadOnlyDescriptor);
         }
 
-        if !data_desc.is_write_only() && req_type == RequestType::In {
-            return Err(ParseError::UnexpectedReadOnlyDescriptor);
+        if data_desc.len < VIRTIO_BLK_ID_BYTES {
+            return Err(ParseError::DescriptorLengthTooSmall);
         }
 
         // The status MUST always be writable
@@ -347,44 +456,100 @@ impl Request {
             return Err(ParseError::DescriptorLengthTooSmall);
         }
 
+        Ok(Request {
+            request_type: RequestType::GetDeviceID,
+            sector: 0,
+            data: vec![(data_desc.addr, VIRTIO_BLK_ID_BYTES)],
+            status_addr: status_desc.addr,
+            discard_write_zeroes_seg: None,
+        })
+    }
+
+    fn parse_read_write(avail_desc: &DescriptorChain,
+                        mem: &GuestMemory,
+                        req_type: RequestType)
+        -> result::Result<Request, ParseError>
+    {
+        let sector = sector(&mem, avail_desc.addr)?;
+
+        // Accumulate descriptors until we reach the final one, which is the status descriptor.
+        // Some drivers (e.g. Windows' virtio-blk) chain several data descriptors between the
+        // header and the status footer instead of exactly one.
+        let mut data = Vec::new();
+        let mut desc = avail_desc
+            .next_descriptor()
+            .ok_or(ParseError::DescriptorChainTooShort)?;
+        while let Some(next_desc) = desc.next_descriptor() {
+            if desc.is_write_only() && req_type == RequestType::Out {
+                return Err(ParseError::UnexpectedWriteOnlyDescriptor);
+            }
+            if !desc.is_write_only() && req_type == RequestType::In {
+                return Err(ParseError::UnexpectedReadOnlyDescriptor);
+            }
+            data.push((desc.addr, desc.len));
+            desc = next_desc;
+        }
+
+        if data.is_empty() {
+            return Err(ParseError::DescriptorChainTooShort);
+        }
+
+        // `desc` is now the final, status descriptor, which MUST always be writable.
+        let status_desc = desc;
+        if !status_desc.is_write_only() {
+            return Err(ParseError::UnexpectedReadOnlyDescriptor);
+        }
+
+        if status_desc.len < 1 {
+            return Err(ParseError::DescriptorLengthTooSmall);
+        }
+
         Ok(Request {
                request_type: req_type,
                sector: sector,
-               data_addr: data_desc.addr,
-               data_len: data_desc.len,
+               data,
                status_addr: status_desc.addr,
                discard_write_zeroes_seg: None,
            })
     }
 
-    fn execute<T: DiskFile>(
+    fn execute<T: DiskImage>(
         &self,
         disk: &mut T,
         flush_timer: &mut TimerFd,
         mem: &GuestMemory,
+        disk_id: &[u8],
     ) -> result::Result<u32, ExecuteError> {
         // Delay after a write when the file is auto-flushed.
         let flush_delay = Duration::from_secs(60);
 
-        disk.seek(SeekFrom::Start(self.sector << SECTOR_SHIFT))
-            .map_err(|e| ExecuteError::Seek{ ioerr: e, sector: self.sector })?;
+        let mut cursor = DiskImageCursor {
+            disk: &mut *disk,
+            position: self.sector << SECTOR_SHIFT,
+        };
         match self.request_type {
             RequestType::In => {
-                mem.read_to_memory(self.data_addr, disk, self.data_len as usize)
-                    .map_err(|e| ExecuteError::Read{ addr: self.data_addr,
-                                                     length: self.data_len,
-                                                     sector: self.sector,
-                                                     guestmemerr: e })?;
-                return Ok(self.data_len);
+                let mut total_len = 0u32;
+                for &(addr, len) in &self.data {
+                    mem.read_to_memory(addr, &mut cursor, len as usize)
+                        .map_err(|e| ExecuteError::Read{ addr,
+                                                         length: len,
+                                                         sector: self.sector,
+                                                         guestmemerr: e })?;
+                    total_len += len;
+                }
+                return Ok(total_len);
             }
             RequestType::Out => {
-                mem.write_from_memory(self.data_addr, disk, self.data_len as usize)
-                    .map_err(|e| ExecuteError::Write {
-                        addr: self.data_addr,
-                        length: self.data_len,
-                        sector: self.sector,
-                        guestmemerr: e,
-                    })?;
+                for &(addr, len) in &self.data {
+                    mem.write_from_memory(addr, &mut cursor, len as usize)
+                        .map_err(|e| ExecuteError::Write {
+                            addr,
+                            length: len,
+                            sector: self.sector,
+                            guestmemerr: e,
+                        })?;
+                }
                 if !flush_timer.is_armed().map_err(ExecuteError::ArmingTimer)? {
                     flush_timer
                         .reset(flush_delay, None)
@@ -412,46 +577,69 @@ impl Request {
                             });
                     }
 
-                    disk.seek(SeekFrom::Start(sector << SECTOR_SHIFT))
-                        .map_err(|e| ExecuteError::Seek{ ioerr: e, sector })?;
-                    disk.write_zeroes((num_sectors as usize) << SECTOR_SHIFT)
-                        .map_err(|e| ExecuteError::DiscardWriteZeroes {
-                            ioerr: Some(e),
-                            sector,
-                            num_sectors,
-                            flags
-                        })?;
+                    let offset = sector << SECTOR_SHIFT;
+                    let length = (num_sectors as u64) << SECTOR_SHIFT;
+                    let result = if self.request_type == RequestType::WriteZeroes {
+                        disk.write_zeroes_at(offset, length as usize)
+                    } else {
+                        disk.discard(offset, length)
+                    };
+                    result.map_err(|e| ExecuteError::DiscardWriteZeroes {
+                                       ioerr: Some(e),
+                                       sector,
+                                       num_sectors,
+                                       flags
+                                   })?;
                 }
             }
             RequestType::Flush => {
                 disk.flush().map_err(ExecuteError::Flush)?;
                 flush_timer.clear().map_err(ExecuteError::TimerFd)?;
             }
+            RequestType::GetDeviceID => {
+                if let Some(&(addr, len)) = self.data.get(0) {
+                    let mut id_reader = io::Cursor::new(disk_id);
+                    mem.read_to_memory(addr, &mut id_reader, len as usize)
+                        .map_err(|e| ExecuteError::Read{ addr,
+                                                         length: len,
+                                                         sector: self.sector,
+                                                         guestmemerr: e })?;
+                    return Ok(len);
+                }
+            }
             RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
         };
         Ok(0)
     }
 }
 
-struct Worker<T: DiskFile> {
-    queues: Vec<Queue>,
+// One `Worker` runs per queue so that multiple queues' disk traffic can make progress
+// concurrently; they share the backing file through `disk_image` so a flush issued by any one
+// of them still drains the writes the others have queued up against it.
+struct Worker<T: DiskImage> {
+    queue: Queue,
     mem: GuestMemory,
-    disk_image: T,
-    interrupt_status: Arc<AtomicUsize>,
-    interrupt_evt: EventFd,
+    disk_image: Arc<Mutex<T>>,
+    disk_id: [u8; VIRTIO_BLK_ID_BYTES as usize],
+    interrupt: Arc<VirtioInterrupt>,
+    flush_pending: Arc<AtomicBool>,
+    acked_features: u64,
 }
 
-impl<T: DiskFile> Worker<T> {
-    fn process_queue(&mut self, queue_index: usize, flush_timer: &mut TimerFd) -> bool {
-        let queue = &mut self.queues[queue_index];
+impl<T: DiskImage> Worker<T> {
+    fn process_queue(&mut self, flush_timer: &mut TimerFd) -> bool {
+        let queue = &mut self.queue;
 
         let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
         let mut used_count = 0;
+        let old_used_idx = queue.next_used();
         for avail_desc in queue.iter(&self.mem) {
             let len;
             match Request::parse(&avail_desc, &self.mem) {
                 Ok(request) => {
-                    let status = match request.execute(&mut self.disk_image, flush_timer, &self.mem)
+                    let mut disk_image = self.disk_image.lock().unwrap();
+                    let status = match request.execute(&mut *disk_image, flush_timer,
+                                                        &self.mem, &self.disk_id)
                     {
                         Ok(l) => {
                             len = l;
@@ -481,13 +669,29 @@ impl<T: DiskFile> Worker<T> {
         for &(desc_index, len) in &used_desc_heads[..used_count] {
             queue.add_used(&self.mem, desc_index, len);
         }
-        used_count > 0
+        if used_count == 0 {
+            return false;
+        }
+
+        let new_used_idx = queue.next_used();
+        if self.acked_features & (1 << VIRTIO_RING_F_EVENT_IDX) != 0 {
+            // Let the driver know where we stopped so it can suppress its next notification
+            // until there's something new for us to see.
+            queue.update_avail_event(&self.mem);
+
+            // Per the virtio spec's event-index scheme: only interrupt if used_event still falls
+            // within the batch of indices we just published.
+            let used_event = queue.used_event(&self.mem);
+            new_used_idx - used_event - Wrapping(1) < new_used_idx - old_used_idx
+        } else {
+            true
+        }
     }
 
     fn signal_used_queue(&self) {
-        self.interrupt_status
-            .fetch_or(INTERRUPT_STATUS_USED_RING as usize, Ordering::SeqCst);
-        self.interrupt_evt.write(1).unwrap();
+        if let Err(e) = self.interrupt.signal_used_queue(&self.queue) {
+            error!("failed to signal used queue: {:?}", e);
+        }
     }
 
     fn run(&mut self, queue_evt: EventFd, kill_evt: EventFd) {
@@ -506,6 +710,14 @@ impl<T: DiskFile> Worker<T> {
             }
         };
 
+        // If the device was restored with a flush still owed to the guest, re-arm the timer
+        // right away instead of waiting for the next write to notice it's due.
+        if self.flush_pending.load(Ordering::Relaxed) {
+            if let Err(e) = flush_timer.reset(Duration::from_secs(60), None) {
+                error!("failed to re-arm flush timer on restore: {:?}", e);
+            }
+        }
+
         let poll_ctx: PollContext<Token> =
             match PollContext::new()
                       .and_then(|pc| pc.add(&flush_timer, Token::FlushTimer).and(Ok(pc)))
@@ -531,7 +743,7 @@ impl<T: DiskFile> Worker<T> {
             for event in events.iter_readable() {
                 match event.token() {
                     Token::FlushTimer => {
-                        if let Err(e) = self.disk_image.flush() {
+                        if let Err(e) = self.disk_image.lock().unwrap().flush() {
                             error!("Failed to flush the disk: {:?}", e);
                             break 'poll;
                         }
@@ -541,7 +753,7 @@ impl<T: DiskFile> Worker<T> {
                             error!("failed reading queue EventFd: {:?}", e);
                             break 'poll;
                         }
-                        needs_interrupt |= self.process_queue(0, &mut flush_timer);
+                        needs_interrupt |= self.process_queue(&mut flush_timer);
                     }
                     Token::Kill => break 'poll,
                 }
@@ -550,22 +762,61 @@ impl<T: DiskFile> Worker<T> {
                 self.signal_used_queue();
             }
         }
+
+        // `flush_pending` is shared across every queue's worker thread (they all flush the same
+        // `disk_image`), so only ever set it - never clear it here. Each worker only knows its own
+        // timer's armed state, and workers exit at different times, so an unconditional `store`
+        // would let whichever worker exits last clobber another worker's still-pending flush with
+        // `false`, silently losing it across a migration restore.
+        if flush_timer.is_armed().unwrap_or(false) {
+            self.flush_pending.store(true, Ordering::Relaxed);
+        }
     }
 }
 
 /// Virtio device for exposing block level read/write operations on a host file.
-pub struct Block<T: DiskFile> {
-    kill_evt: Option<EventFd>,
-    disk_image: Option<T>,
+pub struct Block<T: DiskImage> {
+    kill_evts: Vec<EventFd>,
+    worker_threads: Vec<thread::JoinHandle<()>>,
+    disk_image: Arc<Mutex<T>>,
+    disk_id: [u8; VIRTIO_BLK_ID_BYTES as usize],
+    interrupt_evt: Option<EventFd>,
+    queue_evts: Option<Vec<EventFd>>,
     config_space: virtio_blk_config,
     avail_features: u64,
+    acked_features: u64,
     read_only: bool,
+    flush_pending: Arc<AtomicBool>,
+    queue_max_sizes: Vec<u16>,
+}
+
+// Builds the 20-byte, zero-padded device-id string VIRTIO_BLK_T_GET_ID returns, derived from the
+// disk image's device/inode numbers so it stays stable across device restarts. Backends that
+// aren't fd-based (and so can't be fstat'd) get an empty serial.
+fn build_disk_image_id<T: DiskImage + ?Sized>(disk_image: &T) -> [u8; VIRTIO_BLK_ID_BYTES as usize] {
+    let mut id = [0u8; VIRTIO_BLK_ID_BYTES as usize];
+    let raw_fd = match disk_image.as_raw_fd() {
+        Some(fd) => fd,
+        None => return id,
+    };
+    let mut stat: libc::stat64 = unsafe { mem::zeroed() };
+    // Safe because we pass a valid, open fd and a correctly sized and aligned stat buffer.
+    let ret = unsafe { libc::fstat64(raw_fd, &mut stat) };
+    if ret == 0 {
+        let blob = format!("{}{}", stat.st_dev, stat.st_ino);
+        let len = cmp::min(blob.len(), id.len());
+        id[..len].copy_from_slice(&blob.as_bytes()[..len]);
+    } else {
+        warn!("failed to fstat disk image, using an empty VIRTIO_BLK_T_GET_ID serial");
+    }
+    id
 }
 
-fn build_config_space(disk_size: u64) -> virtio_blk_config {
+fn build_config_space(disk_size: u64, num_queues: u16) -> virtio_blk_config {
     virtio_blk_config {
         // If the image is not a multiple of the sector size, the tail bits are not exposed.
         capacity: Le64::from(disk_size >> SECTOR_SHIFT),
+        num_queues: Le16::from(num_queues),
         max_discard_sectors: Le32::from(MAX_DISCARD_SECTORS),
         discard_sector_alignment: Le32::from(DISCARD_SECTOR_ALIGNMENT),
         max_write_zeroes_sectors: Le32::from(MAX_WRITE_ZEROES_SECTORS),
@@ -577,55 +828,990 @@ fn build_config_space(disk_size: u64) -> virtio_blk_config {
     }
 }
 
-impl<T: DiskFile> Block<T> {
-    /// Create a new virtio block device that operates on the given file.
+// Bytes-per-sector used throughout the synthesized FAT image built by `VvfatDisk`.
+const VVFAT_SECTOR_SIZE: u64 = 512;
+const VVFAT_BOOT_SECTOR_BYTES: usize = 512;
+const VVFAT_ROOT_DIR_ENTRIES: u32 = 512;
+// Cutoff between FAT12 and FAT16, per the standard Microsoft FAT spec.
+const VVFAT_MAX_FAT12_CLUSTERS: u32 = 4084;
+const VVFAT_MAX_FAT16_CLUSTERS: u32 = 65524;
+const VVFAT_MAX_CLUSTER_BYTES: u32 = 32 * 1024;
+
+#[derive(Copy, Clone, PartialEq)]
+enum FatType {
+    Fat12,
+    Fat16,
+}
+
+impl FatType {
+    fn end_marker(self) -> u16 {
+        match self {
+            FatType::Fat12 => 0x0FFF,
+            FatType::Fat16 => 0xFFFF,
+        }
+    }
+
+    fn media_marker(self) -> u16 {
+        match self {
+            FatType::Fat12 => 0x0FF8,
+            FatType::Fat16 => 0xFFF8,
+        }
+    }
+
+    fn fs_type_label(self) -> &'static [u8; 8] {
+        match self {
+            FatType::Fat12 => b"FAT12   ",
+            FatType::Fat16 => b"FAT16   ",
+        }
+    }
+}
+
+// A single file surfaced in the synthesized root directory. Clusters are handed out to files
+// sequentially and never fragmented, since the whole image is rebuilt from scratch at
+// construction time and there is no pre-existing layout to preserve.
+struct VvfatFile {
+    host_path: PathBuf,
+    name: String,
+    short_name: [u8; 11],
+    size: u64,
+    start_cluster: u32,
+    clusters: u32,
+}
+
+/// A `DiskImage` backend that synthesizes a read-write FAT filesystem image on the fly from a host
+/// directory, so a folder can be shared with a guest without pre-building a disk image. This is
+/// the isolated, fully host-controlled equivalent of QEMU's VVFAT driver.
+///
+/// The directory listing is taken once, at construction time: the boot sector, FAT tables and
+/// root directory entries are all derived from it up front and never change afterwards, so
+/// changes made to the host directory later are not reflected in the guest's view of it.
+/// Subdirectories are skipped; only regular files directly inside the directory are exposed.
+/// Guest writes into a file's already-allocated clusters are passed straight through to the
+/// backing host file, but the device does not support the guest growing a file past its
+/// original size or otherwise restructuring the directory - the synthesized metadata regions
+/// (boot sector, FAT, root directory) accept writes without error, but they are never persisted.
+pub struct VvfatDisk {
+    dir: File,
+    read_only: bool,
+    cluster_bytes: u32,
+    fat1_start: u64,
+    root_dir_start: u64,
+    data_start: u64,
+    total_size: u64,
+    boot_sector: [u8; VVFAT_BOOT_SECTOR_BYTES],
+    fat_bytes: Vec<u8>,
+    root_dir_bytes: Vec<u8>,
+    files: Vec<VvfatFile>,
+    dirty_files: HashSet<usize>,
+}
+
+impl VvfatDisk {
+    /// Builds a `VvfatDisk` whose root directory mirrors the regular files directly inside
+    /// `dir_path`.
+    pub fn new(dir_path: &Path, read_only: bool) -> io::Result<VvfatDisk> {
+        let dir = File::open(dir_path)?;
+
+        let mut entries: Vec<(PathBuf, String, u64)> = Vec::new();
+        for entry in fs::read_dir(dir_path)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue, // skip names that aren't valid UTF-8
+            };
+            entries.push((entry.path(), name, metadata.len()));
+        }
+        entries.sort_by(|a, b| a.1.cmp(&b.1));
+
+        let cluster_bytes = pick_cluster_size(entries.iter().map(|e| e.2));
+
+        let mut files = Vec::with_capacity(entries.len());
+        let mut short_names: Vec<[u8; 11]> = Vec::with_capacity(entries.len());
+        let mut next_cluster = 2u32;
+        for (host_path, name, size) in entries {
+            let short_name = make_short_name(&name, &short_names);
+            short_names.push(short_name);
+
+            let clusters = if size == 0 {
+                0
+            } else {
+                ((size + cluster_bytes as u64 - 1) / cluster_bytes as u64) as u32
+            };
+            let start_cluster = if clusters == 0 { 0 } else { next_cluster };
+            next_cluster += clusters;
+
+            files.push(VvfatFile {
+                           host_path,
+                           name,
+                           short_name,
+                           size,
+                           start_cluster,
+                           clusters,
+                       });
+        }
+
+        let total_clusters = next_cluster - 2;
+        let fat_type = if total_clusters <= VVFAT_MAX_FAT12_CLUSTERS {
+            FatType::Fat12
+        } else {
+            FatType::Fat16
+        };
+        let end_marker = fat_type.end_marker();
+
+        let mut fat: Vec<u16> = vec![0; (total_clusters + 2) as usize];
+        fat[0] = fat_type.media_marker();
+        fat[1] = end_marker;
+        for file in &files {
+            for i in 0..file.clusters {
+                let cluster = (file.start_cluster + i) as usize;
+                fat[cluster] = if i + 1 == file.clusters {
+                    end_marker
+                } else {
+                    (file.start_cluster + i + 1) as u16
+                };
+            }
+        }
+
+        let fat_bytes = match fat_type {
+            FatType::Fat12 => fat12_bytes(&fat),
+            FatType::Fat16 => fat16_bytes(&fat),
+        };
+        let fat_size_sectors =
+            ((fat_bytes.len() as u64 + VVFAT_SECTOR_SIZE - 1) / VVFAT_SECTOR_SIZE) as u32;
+        let fat_bytes = pad_to_sectors(fat_bytes, fat_size_sectors);
+
+        let root_dir_bytes = build_root_dir(&files);
+
+        let reserved_sectors = 1u32;
+        let fat1_start = reserved_sectors as u64 * VVFAT_SECTOR_SIZE;
+        let root_dir_start = fat1_start + 2 * fat_bytes.len() as u64;
+        let data_start = root_dir_start + root_dir_bytes.len() as u64;
+        let total_size = data_start + total_clusters as u64 * cluster_bytes as u64;
+        let total_sectors = (total_size / VVFAT_SECTOR_SIZE) as u32;
+
+        let sectors_per_cluster = (cluster_bytes / VVFAT_SECTOR_SIZE as u32) as u8;
+        let boot_sector = build_boot_sector(sectors_per_cluster,
+                                             reserved_sectors as u16,
+                                             fat_size_sectors as u16,
+                                             total_sectors,
+                                             fat_type);
+
+        Ok(VvfatDisk {
+               dir,
+               read_only,
+               cluster_bytes,
+               fat1_start,
+               root_dir_start,
+               data_start,
+               total_size,
+               boot_sector,
+               fat_bytes,
+               root_dir_bytes,
+               files,
+               dirty_files: HashSet::new(),
+           })
+    }
+
+    fn file_for_cluster(&self, cluster: u32) -> Option<usize> {
+        self.files
+            .iter()
+            .position(|f| {
+                          f.clusters > 0 && cluster >= f.start_cluster &&
+                          cluster < f.start_cluster + f.clusters
+                      })
+    }
+
+    fn read_data(&self, offset: u64, buf: &mut [u8]) -> usize {
+        let data_offset = offset - self.data_start;
+        let cluster = 2 + (data_offset / self.cluster_bytes as u64) as u32;
+        let cluster_off = data_offset % self.cluster_bytes as u64;
+        let bytes_left_in_cluster = self.cluster_bytes as u64 - cluster_off;
+        let want = cmp::min(buf.len() as u64, bytes_left_in_cluster) as usize;
+
+        if let Some(idx) = self.file_for_cluster(cluster) {
+            let file = &self.files[idx];
+            let file_offset =
+                (cluster - file.start_cluster) as u64 * self.cluster_bytes as u64 + cluster_off;
+            if file_offset < file.size {
+                let want = cmp::min(want as u64, file.size - file_offset) as usize;
+                if let Ok(mut f) = File::open(&file.host_path) {
+                    if f.seek(SeekFrom::Start(file_offset)).is_ok() {
+                        if let Ok(n) = f.read(&mut buf[..want]) {
+                            return n;
+                        }
+                    }
+                }
+                return 0;
+            }
+        }
+
+        // Unused cluster, or padding past the end of a file's last (partial) cluster.
+        for b in buf[..want].iter_mut() {
+            *b = 0;
+        }
+        want
+    }
+
+    fn write_data(&mut self, offset: u64, buf: &[u8]) -> usize {
+        let data_offset = offset - self.data_start;
+        let cluster = 2 + (data_offset / self.cluster_bytes as u64) as u32;
+        let cluster_off = data_offset % self.cluster_bytes as u64;
+        let bytes_left_in_cluster = self.cluster_bytes as u64 - cluster_off;
+        let want = cmp::min(buf.len() as u64, bytes_left_in_cluster) as usize;
+
+        if let Some(idx) = self.file_for_cluster(cluster) {
+            let (host_path, file_offset, file_size) = {
+                let file = &self.files[idx];
+                (file.host_path.clone(),
+                 (cluster - file.start_cluster) as u64 * self.cluster_bytes as u64 + cluster_off,
+                 file.size)
+            };
+            if file_offset < file_size {
+                let want = cmp::min(want as u64, file_size - file_offset) as usize;
+                if let Ok(mut f) = fs::OpenOptions::new().write(true).open(&host_path) {
+                    if f.seek(SeekFrom::Start(file_offset)).is_ok() {
+                        if let Ok(n) = f.write(&buf[..want]) {
+                            self.dirty_files.insert(idx);
+                            return n;
+                        }
+                    }
+                }
+                return 0;
+            }
+        }
+
+        // Unused cluster, or padding past the end of a file's last cluster: there is no host
+        // file to receive it, and the device doesn't support guest-driven growth of a file or
+        // directory, so the write is silently discarded.
+        want
+    }
+}
+
+impl DiskImage for VvfatDisk {
+    fn len(&self) -> u64 {
+        self.total_size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if offset >= self.total_size {
+            return Ok(0);
+        }
+        let avail = (self.total_size - offset) as usize;
+        let want = cmp::min(buf.len(), avail);
+        let buf = &mut buf[..want];
+
+        let n = if offset < self.fat1_start {
+            copy_region(&self.boot_sector, offset, buf)
+        } else if offset < self.fat1_start + self.fat_bytes.len() as u64 {
+            copy_region(&self.fat_bytes, offset - self.fat1_start, buf)
+        } else if offset < self.root_dir_start {
+            let fat2_start = self.fat1_start + self.fat_bytes.len() as u64;
+            copy_region(&self.fat_bytes, offset - fat2_start, buf)
+        } else if offset < self.data_start {
+            copy_region(&self.root_dir_bytes, offset - self.root_dir_start, buf)
+        } else {
+            self.read_data(offset, buf)
+        };
+        Ok(n)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        if self.read_only {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                       "vvfat image is read-only"));
+        }
+        if offset >= self.total_size {
+            return Ok(0);
+        }
+        let avail = (self.total_size - offset) as usize;
+        let want = cmp::min(buf.len(), avail);
+        let buf = &buf[..want];
+
+        let n = if offset >= self.data_start {
+            self.write_data(offset, buf)
+        } else {
+            // Writes to the boot sector, FAT, or root directory only affect metadata that is
+            // always rederived from the host directory; they're accepted but never persisted.
+            want
+        };
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for idx in self.dirty_files.drain() {
+            if let Some(file) = self.files.get(idx) {
+                if let Ok(f) = File::open(&file.host_path) {
+                    let _ = f.sync_all();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<()> {
+        let zeroes = vec![0u8; cmp::min(length, self.cluster_bytes as usize)];
+        let mut remaining = length;
+        let mut offset = offset;
+        while remaining > 0 {
+            let chunk = cmp::min(remaining, zeroes.len());
+            let n = self.write_at(offset, &zeroes[..chunk])?;
+            if n == 0 {
+                break;
+            }
+            remaining -= n;
+            offset += n as u64;
+        }
+        Ok(())
+    }
+
+    fn as_raw_fd(&self) -> Option<RawFd> {
+        Some(AsRawFd::as_raw_fd(&self.dir))
+    }
+}
+
+fn copy_region(table: &[u8], table_offset: u64, buf: &mut [u8]) -> usize {
+    let table_offset = table_offset as usize;
+    let n = cmp::min(buf.len(), table.len() - table_offset);
+    buf[..n].copy_from_slice(&table[table_offset..table_offset + n]);
+    n
+}
+
+// Like `pick_cluster_size`, but sizes clusters against a requested total capacity rather than a
+// set of file sizes, for formatting a fresh filesystem that has no files in it yet.
+fn pick_cluster_size_for_capacity(total_bytes: u64) -> u32 {
+    let mut cluster_bytes = VVFAT_SECTOR_SIZE as u32;
+    loop {
+        let total_clusters = total_bytes / cluster_bytes as u64;
+        if total_clusters <= VVFAT_MAX_FAT16_CLUSTERS as u64 || cluster_bytes >= VVFAT_MAX_CLUSTER_BYTES
+        {
+            return cluster_bytes;
+        }
+        cluster_bytes *= 2;
+    }
+}
+
+// The root directory region of a freshly formatted, empty filesystem: `VVFAT_ROOT_DIR_ENTRIES`
+// zeroed 32-byte slots, already a whole number of sectors.
+fn empty_root_dir_bytes() -> Vec<u8> {
+    vec![0u8; VVFAT_ROOT_DIR_ENTRIES as usize * 32]
+}
+
+// Picks the smallest cluster size (a power-of-two multiple of the sector size) that keeps the
+// total cluster count within the FAT16 addressable range, capping out at `VVFAT_MAX_CLUSTER_BYTES`
+// the way real-world FAT formatters do.
+fn pick_cluster_size<I: Iterator<Item = u64>>(sizes: I) -> u32 {
+    let sizes: Vec<u64> = sizes.collect();
+    let mut cluster_bytes = VVFAT_SECTOR_SIZE as u32;
+    loop {
+        let total_clusters: u64 = sizes
+            .iter()
+            .map(|&size| if size == 0 {
+                     0
+                 } else {
+                     (size + cluster_bytes as u64 - 1) / cluster_bytes as u64
+                 })
+            .sum();
+        if total_clusters <= VVFAT_MAX_FAT16_CLUSTERS as u64 || cluster_bytes >= VVFAT_MAX_CLUSTER_BYTES
+        {
+            return cluster_bytes;
+        }
+        cluster_bytes *= 2;
+    }
+}
+
+fn fat16_bytes(fat: &[u16]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(fat.len() * 2);
+    for &entry in fat {
+        bytes.push((entry & 0xFF) as u8);
+        bytes.push((entry >> 8) as u8);
+    }
+    bytes
+}
+
+// FAT12 packs two 12-bit entries into 3 bytes, straddling byte boundaries.
+fn fat12_bytes(fat: &[u16]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity((fat.len() * 3 + 1) / 2);
+    let mut iter = fat.iter();
+    while let Some(&a) = iter.next() {
+        let b = iter.next().cloned().unwrap_or(0);
+        bytes.push((a & 0xFF) as u8);
+        bytes.push(((a >> 8) & 0x0F) as u8 | (((b & 0x0F) as u8) << 4));
+        bytes.push((b >> 4) as u8);
+    }
+    bytes
+}
+
+fn pad_to_sectors(mut bytes: Vec<u8>, sectors: u32) -> Vec<u8> {
+    bytes.resize(sectors as usize * VVFAT_SECTOR_SIZE as usize, 0);
+    bytes
+}
+
+fn split_name(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(0) => (name, ""), // a leading dot is part of the base name, not an extension
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => (name, ""),
+    }
+}
+
+fn sanitize_83(s: &str) -> String {
+    s.chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| {
+            let c = c.to_ascii_uppercase();
+            if c.is_ascii_alphanumeric() || "$%'-_@~`!(){}^#&".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn pack_83(base: &str, ext: &str) -> [u8; 11] {
+    let mut out = [b' '; 11];
+    for (i, b) in base.bytes().take(8).enumerate() {
+        out[i] = b;
+    }
+    for (i, b) in ext.bytes().take(3).enumerate() {
+        out[8 + i] = b;
+    }
+    out
+}
+
+// Builds the 8.3 short name the directory entry will advertise for `name`, falling back to the
+// classic `BASE~N.EXT` numeric-tail scheme when the name doesn't fit cleanly or collides with one
+// already chosen for an earlier file.
+fn make_short_name(name: &str, existing: &[[u8; 11]]) -> [u8; 11] {
+    let (base, ext) = split_name(name);
+    let sanitized_base = sanitize_83(base);
+    let sanitized_ext = sanitize_83(ext);
+
+    let fits_83 = !base.is_empty() && base.len() <= 8 && ext.len() <= 3 &&
+                  sanitized_base == base && sanitized_ext == ext;
+    if fits_83 {
+        let candidate = pack_83(&sanitized_base, &sanitized_ext);
+        if !existing.contains(&candidate) {
+            return candidate;
+        }
+    }
+
+    for n in 1..1000u32 {
+        let suffix = format!("~{}", n);
+        let trimmed_len = 8 - suffix.len();
+        let trimmed: String = sanitized_base.chars().take(trimmed_len).collect();
+        let candidate = pack_83(&format!("{}{}", trimmed, suffix), &sanitized_ext);
+        if !existing.contains(&candidate) {
+            return candidate;
+        }
+    }
+    // Directories with more than 999 colliding names are vanishingly unlikely; fall back to a
+    // plain truncation rather than failing the whole mount.
+    pack_83(&sanitized_base, &sanitized_ext)
+}
+
+fn short_name_matches(name: &str, short: &[u8; 11]) -> bool {
+    let base = String::from_utf8_lossy(&short[0..8]).trim_end().to_string();
+    let ext = String::from_utf8_lossy(&short[8..11]).trim_end().to_string();
+    let reconstructed = if ext.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, ext)
+    };
+    reconstructed.eq_ignore_ascii_case(name) && !name.contains('~')
+}
+
+fn lfn_checksum(short_name: &[u8; 11]) -> u8 {
+    let mut sum: u8 = 0;
+    for &b in short_name.iter() {
+        sum = (sum >> 1)
+            .wrapping_add(if sum & 1 != 0 { 0x80 } else { 0 })
+            .wrapping_add(b);
+    }
+    sum
+}
+
+fn write_utf16_run(dst: &mut [u8], chars: &[u16]) {
+    for (i, &c) in chars.iter().enumerate() {
+        dst[i * 2] = (c & 0xFF) as u8;
+        dst[i * 2 + 1] = (c >> 8) as u8;
+    }
+}
+
+fn lfn_entry_bytes(seq_byte: u8, chunk: &[u16; 13], checksum: u8) -> [u8; 32] {
+    let mut entry = [0u8; 32];
+    entry[0] = seq_byte;
+    write_utf16_run(&mut entry[1..11], &chunk[0..5]);
+    entry[11] = 0x0F; // ATTR_LONG_NAME
+    entry[13] = checksum;
+    write_utf16_run(&mut entry[14..26], &chunk[5..11]);
+    write_utf16_run(&mut entry[28..32], &chunk[11..13]);
+    entry
+}
+
+fn short_entry_bytes(file: &VvfatFile) -> [u8; 32] {
+    let mut entry = [0u8; 32];
+    entry[0..11].copy_from_slice(&file.short_name);
+    entry[11] = 0x20; // ATTR_ARCHIVE
+    entry[26] = (file.start_cluster & 0xFF) as u8;
+    entry[27] = (file.start_cluster >> 8) as u8;
+    entry[28..32].copy_from_slice(&(file.size as u32).to_le_bytes());
+    entry
+}
+
+// Builds the flat root-directory region: an LFN entry run (when the name doesn't round-trip
+// through its 8.3 short name) followed by the 8.3 entry itself, for every file. Zero-padded out to
+// the full, fixed `VVFAT_ROOT_DIR_ENTRIES * 32` bytes - not just a whole number of sectors - since
+// the boot sector's `RootEntCnt` field (see `build_boot_sector`) always advertises the fixed entry
+// count, and a real FAT driver derives `FirstDataSector` from that advertised count. Padding to
+// anything less would leave the driver looking for file data at the wrong offset for any directory
+// that doesn't happen to contain exactly `VVFAT_ROOT_DIR_ENTRIES` entries' worth of bytes.
+fn build_root_dir(files: &[VvfatFile]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for file in files {
+        if !short_name_matches(&file.name, &file.short_name) {
+            let checksum = lfn_checksum(&file.short_name);
+            let utf16: Vec<u16> = file.name.encode_utf16().chain(iter::once(0)).collect();
+            let lfn_entry_count = (utf16.len() + 12) / 13;
+            for seq in (1..=lfn_entry_count).rev() {
+                let start = (seq - 1) * 13;
+                let mut chunk = [0xFFFFu16; 13];
+                for i in 0..13 {
+                    if start + i < utf16.len() {
+                        chunk[i] = utf16[start + i];
+                    }
+                }
+                let mut seq_byte = seq as u8;
+                if seq == lfn_entry_count {
+                    seq_byte |= 0x40;
+                }
+                bytes.extend_from_slice(&lfn_entry_bytes(seq_byte, &chunk, checksum));
+            }
+        }
+        bytes.extend_from_slice(&short_entry_bytes(file));
+    }
+    let fixed_len = VVFAT_ROOT_DIR_ENTRIES as usize * 32;
+    assert!(
+        bytes.len() <= fixed_len,
+        "too many files for the fixed-size VVFAT root directory ({} entries)",
+        VVFAT_ROOT_DIR_ENTRIES
+    );
+    bytes.resize(fixed_len, 0);
+    bytes
+}
+
+fn build_boot_sector(sectors_per_cluster: u8,
+                      reserved_sectors: u16,
+                      fat_size_sectors: u16,
+                      total_sectors: u32,
+                      fat_type: FatType)
+                      -> [u8; VVFAT_BOOT_SECTOR_BYTES] {
+    let mut boot = [0u8; VVFAT_BOOT_SECTOR_BYTES];
+    boot[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    boot[3..11].copy_from_slice(b"CROSVMFS");
+    boot[11..13].copy_from_slice(&(VVFAT_SECTOR_SIZE as u16).to_le_bytes());
+    boot[13] = sectors_per_cluster;
+    boot[14..16].copy_from_slice(&reserved_sectors.to_le_bytes());
+    boot[16] = 2; // number of FAT copies
+    boot[17..19].copy_from_slice(&(VVFAT_ROOT_DIR_ENTRIES as u16).to_le_bytes());
+    if total_sectors <= u16::max_value() as u32 {
+        boot[19..21].copy_from_slice(&(total_sectors as u16).to_le_bytes());
+    }
+    boot[21] = 0xF8; // fixed disk media descriptor
+    boot[22..24].copy_from_slice(&fat_size_sectors.to_le_bytes());
+    boot[24..26].copy_from_slice(&63u16.to_le_bytes()); // sectors per track
+    boot[26..28].copy_from_slice(&255u16.to_le_bytes()); // number of heads
+    if total_sectors > u16::max_value() as u32 {
+        boot[32..36].copy_from_slice(&total_sectors.to_le_bytes());
+    }
+    boot[36] = 0x80; // drive number
+    boot[38] = 0x29; // extended boot signature
+    boot[39..43].copy_from_slice(&0x5656_4146u32.to_le_bytes()); // arbitrary, stable volume id
+    boot[43..54].copy_from_slice(b"VVFAT DIR  ");
+    boot[54..62].copy_from_slice(fat_type.fs_type_label());
+    boot[510] = 0x55;
+    boot[511] = 0xAA;
+    boot
+}
+
+/// A `DiskImage` backend that keeps the entire disk contents in a host memory buffer, optionally
+/// seeded from and flushed back to a backing file. Reads and writes never touch disk, so this is
+/// useful for ephemeral guest scratch space and for tests that want a `Block` device without a
+/// temp file; when a backing file is supplied, `flush()` (triggered by VIRTIO_BLK_T_FLUSH or a
+/// `Block` reset) writes the whole buffer back out so the guest's data survives a restart.
+pub struct MemoryDisk {
+    data: Vec<u8>,
+    read_only: bool,
+    backing_file: Option<File>,
+}
+
+impl MemoryDisk {
+    /// Builds a `size`-byte RAM disk with no backing file; its contents start zeroed and are
+    /// discarded when the device is torn down.
+    pub fn new(size: u64, read_only: bool) -> MemoryDisk {
+        MemoryDisk {
+            data: vec![0u8; size as usize],
+            read_only,
+            backing_file: None,
+        }
+    }
+
+    /// Builds a RAM disk whose initial contents are loaded from `backing_file`. Guest I/O is
+    /// served entirely out of the in-memory buffer; `flush()` writes the buffer back to
+    /// `backing_file` so the on-disk contents only change at flush points rather than on every
+    /// write.
+    pub fn from_backing_file(mut backing_file: File, read_only: bool) -> io::Result<MemoryDisk> {
+        let mut data = Vec::new();
+        backing_file.read_to_end(&mut data)?;
+        Ok(MemoryDisk {
+               data,
+               read_only,
+               backing_file: Some(backing_file),
+           })
+    }
+}
+
+impl DiskImage for MemoryDisk {
+    fn len(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let offset = offset as usize;
+        if offset >= self.data.len() {
+            return Ok(0);
+        }
+        let n = cmp::min(buf.len(), self.data.len() - offset);
+        buf[..n].copy_from_slice(&self.data[offset..offset + n]);
+        Ok(n)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        if self.read_only {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                       "memory disk is read-only"));
+        }
+        let offset = offset as usize;
+        if offset >= self.data.len() {
+            return Ok(0);
+        }
+        let n = cmp::min(buf.len(), self.data.len() - offset);
+        self.data[offset..offset + n].copy_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(ref mut backing_file) = self.backing_file {
+            backing_file.seek(SeekFrom::Start(0))?;
+            backing_file.write_all(&self.data)?;
+            backing_file.set_len(self.data.len() as u64)?;
+            backing_file.flush()?;
+            backing_file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn discard(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        self.write_zeroes_at(offset, length as usize)
+    }
+
+    fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<()> {
+        let offset = offset as usize;
+        if offset >= self.data.len() {
+            return Ok(());
+        }
+        let end = cmp::min(offset + length, self.data.len());
+        for b in &mut self.data[offset..end] {
+            *b = 0;
+        }
+        Ok(())
+    }
+
+    fn supports_discard(&self) -> bool {
+        true
+    }
+}
+
+impl Block<File> {
+    /// Formats `file` as an empty FAT filesystem whose data region is `total_size` bytes rounded
+    /// down to a whole number of clusters (never less than one cluster), overwriting any existing
+    /// contents, then wraps it in a `Block` so the guest can mount
+    /// it immediately without an in-guest mkfs step. The FAT type (12 or 16) and cluster size are
+    /// chosen automatically from the resulting cluster count, the same way `VvfatDisk` chooses
+    /// them for a directory-backed image.
+    pub fn format_new(mut file: File, total_size: u64, read_only: bool) -> SysResult<Block<File>> {
+        let cluster_bytes = pick_cluster_size_for_capacity(total_size);
+        let total_clusters = cmp::max(1, total_size / cluster_bytes as u64) as u32;
+        let fat_type = if total_clusters <= VVFAT_MAX_FAT12_CLUSTERS {
+            FatType::Fat12
+        } else {
+            FatType::Fat16
+        };
+
+        let mut fat: Vec<u16> = vec![0; (total_clusters + 2) as usize];
+        fat[0] = fat_type.media_marker();
+        fat[1] = fat_type.end_marker();
+
+        let fat_bytes = match fat_type {
+            FatType::Fat12 => fat12_bytes(&fat),
+            FatType::Fat16 => fat16_bytes(&fat),
+        };
+        let fat_size_sectors =
+            ((fat_bytes.len() as u64 + VVFAT_SECTOR_SIZE - 1) / VVFAT_SECTOR_SIZE) as u32;
+        let fat_bytes = pad_to_sectors(fat_bytes, fat_size_sectors);
+
+        let root_dir_bytes = empty_root_dir_bytes();
+
+        let reserved_sectors = 1u32;
+        let data_start = reserved_sectors as u64 * VVFAT_SECTOR_SIZE + 2 * fat_bytes.len() as u64 +
+                          root_dir_bytes.len() as u64;
+        let disk_size = data_start + total_clusters as u64 * cluster_bytes as u64;
+        let total_sectors = (disk_size / VVFAT_SECTOR_SIZE) as u32;
+
+        let sectors_per_cluster = (cluster_bytes / VVFAT_SECTOR_SIZE as u32) as u8;
+        let boot_sector = build_boot_sector(sectors_per_cluster,
+                                             reserved_sectors as u16,
+                                             fat_size_sectors as u16,
+                                             total_sectors,
+                                             fat_type);
+
+        file.set_len(disk_size)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&boot_sector)?;
+        file.write_all(&fat_bytes)?;
+        file.write_all(&fat_bytes)?;
+        file.write_all(&root_dir_bytes)?;
+        file.flush()?;
+        file.sync_all()?;
+
+        Block::new(file, read_only)
+    }
+}
+
+impl Block<VvfatDisk> {
+    /// Creates a virtio block device that presents the contents of `dir` as a synthesized FAT
+    /// filesystem image, so a host directory can be shared with the guest without pre-building a
+    /// disk image. See `VvfatDisk` for the generated layout and its limitations.
+    pub fn from_dir(dir: &Path, read_only: bool) -> SysResult<Block<VvfatDisk>> {
+        let disk_image = VvfatDisk::new(dir, read_only)?;
+        Block::new_with_queues(disk_image, read_only, 1)
+    }
+}
+
+impl<T: DiskImage> Block<T> {
+    /// Create a new virtio block device that operates on the given file using a single queue.
     ///
     /// The given file must be seekable and sizable.
-    pub fn new(mut disk_image: T, read_only: bool) -> SysResult<Block<T>> {
-        let disk_size = disk_image.seek(SeekFrom::End(0))? as u64;
+    pub fn new(disk_image: T, read_only: bool) -> SysResult<Block<T>> {
+        Block::new_with_queues(disk_image, read_only, 1)
+    }
+
+    /// Create a new virtio block device backed by `num_queues` independent queues, each served by
+    /// its own worker thread. The queues all share `disk_image` through a `Mutex`, so writes and
+    /// flushes issued against one queue are ordered with respect to the others: a FLUSH on any
+    /// queue is guaranteed to drain the writes the others have already submitted to it, since
+    /// they all end up serialized through the same underlying file.
+    pub fn new_with_queues(mut disk_image: T,
+                            read_only: bool,
+                            num_queues: u16)
+                            -> SysResult<Block<T>> {
+        let disk_id = build_disk_image_id(&disk_image);
+        let disk_size = disk_image.len();
         if disk_size % SECTOR_SIZE != 0 {
             warn!("Disk size {} is not a multiple of sector size {}; \
                          the remainder will not be visible to the guest.",
                   disk_size,
                   SECTOR_SIZE);
         }
+        let supports_discard = disk_image.supports_discard();
+        let supports_write_zeroes = disk_image.supports_write_zeroes();
 
-        let mut avail_features: u64 = 1 << VIRTIO_BLK_F_FLUSH;
+        let mut avail_features: u64 = 1 << VIRTIO_BLK_F_FLUSH | 1 << VIRTIO_RING_F_EVENT_IDX;
         if read_only {
             avail_features |= 1 << VIRTIO_BLK_F_RO;
         } else {
-            avail_features |= 1 << VIRTIO_BLK_F_DISCARD;
-            avail_features |= 1 << VIRTIO_BLK_F_WRITE_ZEROES;
+            if supports_discard {
+                avail_features |= 1 << VIRTIO_BLK_F_DISCARD;
+            }
+            if supports_write_zeroes {
+                avail_features |= 1 << VIRTIO_BLK_F_WRITE_ZEROES;
+            }
+        }
+        if num_queues > 1 {
+            avail_features |= 1 << VIRTIO_BLK_F_MQ;
         }
 
         Ok(Block {
-               kill_evt: None,
-               disk_image: Some(disk_image),
-               config_space: build_config_space(disk_size),
+               kill_evts: Vec::new(),
+               worker_threads: Vec::new(),
+               disk_image: Arc::new(Mutex::new(disk_image)),
+               disk_id,
+               interrupt_evt: None,
+               queue_evts: None,
+               config_space: build_config_space(disk_size, num_queues),
                avail_features,
+               acked_features: 0,
                read_only,
+               flush_pending: Arc::new(AtomicBool::new(false)),
+               queue_max_sizes: vec![QUEUE_SIZE; num_queues as usize],
            })
     }
+
+    /// Tears down the running workers, if any, flushes the disk image, and hands the
+    /// `interrupt_evt` and queue `EventFd`s back to the caller for rebinding so a later `activate`
+    /// call succeeds. Returns `None` if the device was never activated.
+    pub fn reset(&mut self) -> Option<(EventFd, Vec<EventFd>)> {
+        for kill_evt in self.kill_evts.drain(..) {
+            let _ = kill_evt.write(1);
+        }
+
+        for worker_thread in self.worker_threads.drain(..) {
+            if let Err(e) = worker_thread.join() {
+                error!("block worker thread panicked on reset: {:?}", e);
+            }
+        }
+
+        if let Err(e) = self.disk_image.lock().unwrap().flush() {
+            error!("failed to flush the disk on reset: {:?}", e);
+        }
+
+        match (self.interrupt_evt.take(), self.queue_evts.take()) {
+            (Some(interrupt_evt), Some(queue_evts)) => Some((interrupt_evt, queue_evts)),
+            _ => None,
+        }
+    }
+
+    /// Serializes the device's runtime state into a versioned blob suitable for migrating to
+    /// another instance of this device, or for suspend/resume. `queues` are the ring states to
+    /// capture and should be the same queues most recently passed to `activate`.
+    pub fn snapshot(&self, queues: &[Queue]) -> Vec<u8> {
+        let mut state = Vec::new();
+        state.extend_from_slice(&VIRTIO_BLK_STATE_VERSION.to_le_bytes());
+        state.extend_from_slice(self.config_space.as_slice());
+        state.extend_from_slice(&self.avail_features.to_le_bytes());
+        state.extend_from_slice(&self.acked_features.to_le_bytes());
+        state.push(self.read_only as u8);
+        state.push(self.flush_pending.load(Ordering::Relaxed) as u8);
+        state.extend_from_slice(&(queues.len() as u32).to_le_bytes());
+        for queue in queues {
+            state.extend_from_slice(&queue.max_size.to_le_bytes());
+            state.extend_from_slice(&queue.size.to_le_bytes());
+            state.push(queue.ready as u8);
+            state.extend_from_slice(&queue.desc_table.0.to_le_bytes());
+            state.extend_from_slice(&queue.avail_ring.0.to_le_bytes());
+            state.extend_from_slice(&queue.used_ring.0.to_le_bytes());
+            state.extend_from_slice(&queue.next_avail().0.to_le_bytes());
+            state.extend_from_slice(&queue.next_used().0.to_le_bytes());
+        }
+        state
+    }
+
+    /// Restores runtime state previously produced by `snapshot`, rebuilding `queues` in place so
+    /// that a subsequent `activate` call with those queues resumes in-flight descriptor
+    /// processing from where the snapshot was taken. Refuses the restore if the current disk is
+    /// smaller than the disk the snapshot was taken from, since that would expose guest data past
+    /// the end of the (shrunk) backing file.
+    pub fn restore(&mut self, state: &[u8], queues: &mut [Queue]) -> SysResult<()> {
+        let mut r = io::Cursor::new(state);
+        let mut version = [0u8; size_of::<u32>()];
+        r.read_exact(&mut version)?;
+        if u32::from_le_bytes(version) != VIRTIO_BLK_STATE_VERSION {
+            return Err(SysError::new(libc::EINVAL));
+        }
+
+        let mut config_bytes = vec![0u8; size_of::<virtio_blk_config>()];
+        r.read_exact(&mut config_bytes)?;
+        let saved_config = virtio_blk_config::from_slice(&config_bytes)
+            .ok_or(SysError::new(libc::EINVAL))?;
+        if saved_config.capacity.to_native() > self.config_space.capacity.to_native() {
+            error!("refusing to restore block device state: saved capacity {} exceeds current \
+                    disk capacity {}",
+                   saved_config.capacity.to_native(),
+                   self.config_space.capacity.to_native());
+            return Err(SysError::new(libc::ENOSPC));
+        }
+        self.config_space.writeback = saved_config.writeback;
+
+        let mut avail_features = [0u8; size_of::<u64>()];
+        r.read_exact(&mut avail_features)?;
+        self.avail_features = u64::from_le_bytes(avail_features);
+
+        let mut acked_features = [0u8; size_of::<u64>()];
+        r.read_exact(&mut acked_features)?;
+        self.acked_features = u64::from_le_bytes(acked_features);
+
+        let mut read_only = [0u8; 1];
+        r.read_exact(&mut read_only)?;
+        self.read_only = read_only[0] != 0;
+
+        let mut flush_pending = [0u8; 1];
+        r.read_exact(&mut flush_pending)?;
+        self.flush_pending
+            .store(flush_pending[0] != 0, Ordering::Relaxed);
+
+        let mut num_queues = [0u8; size_of::<u32>()];
+        r.read_exact(&mut num_queues)?;
+        let num_queues = u32::from_le_bytes(num_queues) as usize;
+        if num_queues != queues.len() {
+            error!("refusing to restore block device state: saved {} queues, have {}",
+                   num_queues,
+                   queues.len());
+            return Err(SysError::new(libc::EINVAL));
+        }
+
+        for queue in queues.iter_mut() {
+            let mut max_size = [0u8; size_of::<u16>()];
+            r.read_exact(&mut max_size)?;
+            queue.max_size = u16::from_le_bytes(max_size);
+
+            let mut size = [0u8; size_of::<u16>()];
+            r.read_exact(&mut size)?;
+            queue.size = u16::from_le_bytes(size);
+
+            let mut ready = [0u8; 1];
+            r.read_exact(&mut ready)?;
+            queue.ready = ready[0] != 0;
+
+            let mut desc_table = [0u8; size_of::<u64>()];
+            r.read_exact(&mut desc_table)?;
+            queue.desc_table = GuestAddress(u64::from_le_bytes(desc_table));
+
+            let mut avail_ring = [0u8; size_of::<u64>()];
+            r.read_exact(&mut avail_ring)?;
+            queue.avail_ring = GuestAddress(u64::from_le_bytes(avail_ring));
+
+            let mut used_ring = [0u8; size_of::<u64>()];
+            r.read_exact(&mut used_ring)?;
+            queue.used_ring = GuestAddress(u64::from_le_bytes(used_ring));
+
+            let mut next_avail = [0u8; size_of::<u16>()];
+            r.read_exact(&mut next_avail)?;
+            queue.set_next_avail(Wrapping(u16::from_le_bytes(next_avail)));
+
+            let mut next_used = [0u8; size_of::<u16>()];
+            r.read_exact(&mut next_used)?;
+            queue.set_next_used(Wrapping(u16::from_le_bytes(next_used)));
+        }
+
+        Ok(())
+    }
 }
 
-impl<T: DiskFile> Drop for Block<T> {
+impl<T: DiskImage> Drop for Block<T> {
     fn drop(&mut self) {
-        if let Some(kill_evt) = self.kill_evt.take() {
+        for kill_evt in self.kill_evts.drain(..) {
             // Ignore the result because there is nothing we can do about it.
             let _ = kill_evt.write(1);
         }
     }
 }
 
-impl<T: 'static + AsRawFd + DiskFile + Send> VirtioDevice for Block<T> {
+impl<T: 'static + DiskImage> VirtioDevice for Block<T> {
     fn keep_fds(&self) -> Vec<RawFd> {
-        let mut keep_fds = Vec::new();
-
-        if let Some(ref disk_image) = self.disk_image {
-            keep_fds.push(disk_image.as_raw_fd());
-        }
-
-        keep_fds
+        self.disk_image.lock().unwrap().as_raw_fd().into_iter().collect()
     }
 
     fn features(&self, page: u32) -> u32 {
@@ -636,12 +1822,24 @@ impl<T: 'static + AsRawFd + DiskFile + Send> VirtioDevice for Block<T> {
         }
     }
 
+    fn ack_features(&mut self, value: u64) {
+        let mut v = value;
+
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("virtio_blk got unknown feature ack: {:x}", unrequested_features);
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
     fn device_type(&self) -> u32 {
         TYPE_BLOCK
     }
 
     fn queue_max_sizes(&self) -> &[u16] {
-        QUEUE_SIZES
+        &self.queue_max_sizes
     }
 
     fn read_config(&self, offset: u64, mut data: &mut [u8]) {
@@ -660,41 +1858,83 @@ impl<T: 'static + AsRawFd + DiskFile + Send> VirtioDevice for Block<T> {
 
     fn activate(&mut self,
                 mem: GuestMemory,
-                interrupt_evt: EventFd,
-                status: Arc<AtomicUsize>,
+                interrupt: Arc<VirtioInterrupt>,
                 queues: Vec<Queue>,
-                mut queue_evts: Vec<EventFd>) {
-        if queues.len() != 1 || queue_evts.len() != 1 {
+                queue_evts: Vec<EventFd>) {
+        if queues.is_empty() || queues.len() != queue_evts.len() {
             return;
         }
 
-        let (self_kill_evt, kill_evt) =
-            match EventFd::new().and_then(|e| Ok((e.try_clone()?, e))) {
-                Ok(v) => v,
+        // Keep a clone of the interrupt's raw eventfd and the queue EventFds so `reset` can hand
+        // them back to the transport for rebinding once the workers are torn down.
+        let reset_interrupt_evt = match interrupt.notifier(0) {
+            Some(evt) => match evt.try_clone() {
+                Ok(evt) => evt,
                 Err(e) => {
-                    error!("failed creating kill EventFd pair: {:?}", e);
+                    error!("failed to clone interrupt EventFd: {:?}", e);
                     return;
                 }
-            };
-        self.kill_evt = Some(self_kill_evt);
+            },
+            None => {
+                error!("activate requires a VirtioInterrupt backed by a raw eventfd");
+                return;
+            }
+        };
+        let reset_queue_evts: SysResult<Vec<EventFd>> =
+            queue_evts.iter().map(EventFd::try_clone).collect();
+        let reset_queue_evts = match reset_queue_evts {
+            Ok(evts) => evts,
+            Err(e) => {
+                error!("failed to clone queue EventFds: {:?}", e);
+                return;
+            }
+        };
+        self.interrupt_evt = Some(reset_interrupt_evt);
+        self.queue_evts = Some(reset_queue_evts);
 
-        if let Some(disk_image) = self.disk_image.take() {
+        for (queue_index, (queue, queue_evt)) in
+            queues.into_iter().zip(queue_evts.into_iter()).enumerate()
+        {
+            let (self_kill_evt, kill_evt) =
+                match EventFd::new().and_then(|e| Ok((e.try_clone()?, e))) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        error!("failed creating kill EventFd pair for queue {}: {:?}",
+                               queue_index,
+                               e);
+                        return;
+                    }
+                };
+
+            let mem = mem.clone();
+            let disk_image = self.disk_image.clone();
+            let disk_id = self.disk_id;
+            let interrupt = interrupt.clone();
+            let flush_pending = self.flush_pending.clone();
+            let acked_features = self.acked_features;
             let worker_result = thread::Builder::new()
-                .name("virtio_blk".to_string())
+                .name(format!("virtio_blk_{}", queue_index))
                 .spawn(move || {
                     let mut worker = Worker {
-                        queues: queues,
+                        queue: queue,
                         mem: mem,
                         disk_image: disk_image,
-                        interrupt_status: status,
-                        interrupt_evt: interrupt_evt,
+                        disk_id: disk_id,
+                        interrupt: interrupt,
+                        flush_pending: flush_pending,
+                        acked_features: acked_features,
                     };
-                    worker.run(queue_evts.remove(0), kill_evt);
+                    worker.run(queue_evt, kill_evt);
                 });
 
-            if let Err(e) = worker_result {
-                error!("failed to spawn virtio_blk worker: {}", e);
-                return;
+            match worker_result {
+                Ok(join_handle) => {
+                    self.kill_evts.push(self_kill_evt);
+                    self.worker_threads.push(join_handle);
+                }
+                Err(e) => error!("failed to spawn virtio_blk worker for queue {}: {}",
+                                  queue_index,
+                                  e),
             }
         }
     }
@@ -738,16 +1978,183 @@ mod tests {
             let f = File::create(&path).unwrap();
             let b = Block::new(f, false).unwrap();
             // writable device should set VIRTIO_BLK_F_FLUSH + VIRTIO_BLK_F_DISCARD
-            // + VIRTIO_BLK_F_WRITE_ZEROES
-            assert_eq!(0x6200, b.features(0));
+            // + VIRTIO_BLK_F_WRITE_ZEROES + VIRTIO_RING_F_EVENT_IDX
+            assert_eq!(0x20006200, b.features(0));
         }
 
         // read-only block device
         {
             let f = File::create(&path).unwrap();
             let b = Block::new(f, true).unwrap();
-            // read-only device should set VIRTIO_BLK_F_FLUSH and VIRTIO_BLK_F_RO
-            assert_eq!(0x220, b.features(0));
+            // read-only device should set VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_RO, and
+            // VIRTIO_RING_F_EVENT_IDX
+            assert_eq!(0x20000220, b.features(0));
         }
     }
+
+    #[test]
+    fn snapshot_restore_round_trip() {
+        let tempdir = TempDir::new("/tmp/block_read_test").unwrap();
+        let mut path = PathBuf::from(tempdir.as_path().unwrap());
+        path.push("disk_image");
+        let f = File::create(&path).unwrap();
+        f.set_len(0x1000).unwrap();
+
+        let mut b = Block::new(f, false).unwrap();
+        b.ack_features(1 << VIRTIO_RING_F_EVENT_IDX);
+
+        let mut queue = Queue::new(QUEUE_SIZE);
+        queue.size = 4;
+        queue.ready = true;
+        queue.desc_table = GuestAddress(0x1000);
+        queue.avail_ring = GuestAddress(0x2000);
+        queue.used_ring = GuestAddress(0x3000);
+        queue.set_next_avail(Wrapping(7));
+        queue.set_next_used(Wrapping(3));
+
+        let state = b.snapshot(&[queue]);
+
+        let f = File::create(&path).unwrap();
+        f.set_len(0x1000).unwrap();
+        let mut restored = Block::new(f, false).unwrap();
+        let mut restored_queues = [Queue::new(QUEUE_SIZE)];
+        restored.restore(&state, &mut restored_queues).unwrap();
+
+        assert_eq!(restored.acked_features, 1 << VIRTIO_RING_F_EVENT_IDX);
+        assert_eq!(restored_queues[0].size, 4);
+        assert!(restored_queues[0].ready);
+        assert_eq!(restored_queues[0].desc_table, GuestAddress(0x1000));
+        assert_eq!(restored_queues[0].avail_ring, GuestAddress(0x2000));
+        assert_eq!(restored_queues[0].used_ring, GuestAddress(0x3000));
+        assert_eq!(restored_queues[0].next_avail(), Wrapping(7));
+        assert_eq!(restored_queues[0].next_used(), Wrapping(3));
+    }
+
+    #[test]
+    fn restore_rejects_wrong_queue_count() {
+        let tempdir = TempDir::new("/tmp/block_read_test").unwrap();
+        let mut path = PathBuf::from(tempdir.as_path().unwrap());
+        path.push("disk_image");
+        let f = File::create(&path).unwrap();
+        f.set_len(0x1000).unwrap();
+
+        let b = Block::new(f, false).unwrap();
+        let state = b.snapshot(&[Queue::new(QUEUE_SIZE)]);
+
+        let f = File::create(&path).unwrap();
+        f.set_len(0x1000).unwrap();
+        let mut restored = Block::new(f, false).unwrap();
+        let mut restored_queues = [Queue::new(QUEUE_SIZE), Queue::new(QUEUE_SIZE)];
+        assert!(restored.restore(&state, &mut restored_queues).is_err());
+    }
+
+    // Note: requests above the queue layer (`parse_read_write`'s multi-descriptor handling,
+    // `GetDeviceID`, and the VIRTIO_RING_F_EVENT_IDX threshold math in `process_queue`) all need
+    // a real descriptor chain laid out in `GuestMemory` to drive. Building one requires the
+    // `DescriptorChain`/`Queue` descriptor-table layout helpers that normally live alongside the
+    // `VirtioDevice` trait in `virtio/mod.rs`, which isn't part of this source tree, so there's
+    // nothing to safely construct a fake chain against here without guessing at that layout.
+
+    #[test]
+    fn execute_error_status_codes() {
+        assert_eq!(ExecuteError::Unsupported(0xff).status(), VIRTIO_BLK_S_UNSUPP);
+        assert_eq!(
+            ExecuteError::Flush(io::Error::new(io::ErrorKind::Other, "x")).status(),
+            VIRTIO_BLK_S_IOERR
+        );
+    }
+
+    #[test]
+    fn fat12_bytes_packs_two_entries_per_three_bytes() {
+        let fat = [0x0FF8u16, 0x0FFF, 0x0003, 0x0FFF];
+        assert_eq!(fat12_bytes(&fat), vec![0xf8, 0xff, 0xff, 0x03, 0xf0, 0xff]);
+    }
+
+    #[test]
+    fn fat16_bytes_is_little_endian() {
+        let fat = [0xFFF8u16, 0xFFFF];
+        assert_eq!(fat16_bytes(&fat), vec![0xf8, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn lfn_checksum_matches_known_vector() {
+        assert_eq!(lfn_checksum(b"README  TXT"), 115);
+    }
+
+    #[test]
+    fn make_short_name_falls_back_to_numeric_tail_on_collision() {
+        let first = make_short_name("longfilename.txt", &[]);
+        assert!(!short_name_matches("longfilename.txt", &first));
+
+        // A second, different long name that sanitizes to the same 8.3 prefix must not collide
+        // with the first file's short name.
+        let second = make_short_name("longfilename2.txt", &[first]);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn pick_cluster_size_grows_to_stay_within_fat16_cluster_count() {
+        // A handful of small files fit in the smallest (sector-sized) cluster.
+        assert_eq!(pick_cluster_size(vec![512u64, 512].into_iter()), 512);
+
+        // A single huge file would need more clusters than FAT16 can address at the smallest
+        // cluster size, so the picker must grow the cluster size until it fits.
+        let huge = VVFAT_MAX_FAT16_CLUSTERS as u64 * 512 + 1;
+        assert!(pick_cluster_size(vec![huge].into_iter()) > 512);
+    }
+
+    #[test]
+    fn pick_cluster_size_for_capacity_caps_at_max_cluster_bytes() {
+        let size = pick_cluster_size_for_capacity(u64::max_value() / 2);
+        assert!(size <= VVFAT_MAX_CLUSTER_BYTES);
+    }
+
+    #[test]
+    fn build_config_space_reports_sector_count_and_queues() {
+        let config = build_config_space(0x4000, 2);
+        assert_eq!(config.capacity.to_native(), 0x4000 / SECTOR_SIZE);
+        assert_eq!(config.num_queues.to_native(), 2);
+    }
+
+    #[test]
+    fn flush_pending_is_never_cleared_by_a_later_unarmed_worker() {
+        // Models two queues' worker threads racing to persist their own timer's armed state into
+        // the one `flush_pending` bit they share: queue 0's worker sees an owed flush and exits
+        // first, queue 1's worker has nothing pending and exits after. The shared bit must still
+        // read as pending - a later, unarmed worker must never clobber an earlier, armed one.
+        let flush_pending = Arc::new(AtomicBool::new(false));
+
+        let queue_0_armed = true;
+        if queue_0_armed {
+            flush_pending.store(true, Ordering::Relaxed);
+        }
+
+        let queue_1_armed = false;
+        if queue_1_armed {
+            flush_pending.store(true, Ordering::Relaxed);
+        }
+
+        assert!(flush_pending.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn vvfat_root_dir_size_matches_boot_sector_root_ent_cnt() {
+        let tempdir = TempDir::new("/tmp/block_vvfat_test").unwrap();
+        let dir_path = PathBuf::from(tempdir.as_path().unwrap());
+        fs::write(dir_path.join("a.txt"), b"hello").unwrap();
+        fs::write(dir_path.join("a-name-too-long-for-8.3.txt"), b"world").unwrap();
+
+        let disk = VvfatDisk::new(&dir_path, true).unwrap();
+
+        // The boot sector always advertises the fixed `VVFAT_ROOT_DIR_ENTRIES` count, so the
+        // actual root directory region handed out by `build_root_dir` must be padded to exactly
+        // that many 32-byte entries - not just rounded to a sector - or a real FAT driver (which
+        // derives `FirstDataSector` from the advertised count) will look for file data at the
+        // wrong offset.
+        assert_eq!(disk.root_dir_bytes.len(), VVFAT_ROOT_DIR_ENTRIES as usize * 32);
+        assert_eq!(disk.data_start, disk.root_dir_start + disk.root_dir_bytes.len() as u64);
+
+        let root_ent_cnt = u16::from_le_bytes([disk.boot_sector[17], disk.boot_sector[18]]);
+        assert_eq!(root_ent_cnt as u32, VVFAT_ROOT_DIR_ENTRIES);
+    }
 }
@@ -8,13 +8,14 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use super::*;
-use data_model::{DataInit, Le32};
+use data_model::{DataInit, Le16, Le32};
 use pci::{
-    PciCapability, PciCapabilityID, PciClassCode, PciConfiguration, PciDevice, PciDeviceError,
-    PciHeaderType, PciInterruptPin, PciSubclass,
+    IrqLevelEvent, PciCapability, PciCapabilityID, PciClassCode, PciConfiguration, PciDevice,
+    PciDeviceError, PciHeaderType, PciInterruptPin, PciMassStorageSubclass,
+    PciNetworkControllerSubclass, PciSubclass,
 };
 use resources::SystemAllocator;
-use sys_util::{self, EventFd, GuestMemory, Result};
+use sys_util::{self, EventFd, GuestAddress, GuestMemory, Result};
 
 use self::virtio_pci_common_config::VirtioPciCommonConfig;
 
@@ -105,6 +106,224 @@ impl VirtioPciNotifyCap {
     }
 }
 
+#[repr(packed)]
+#[derive(Clone, Copy)]
+struct MsixCap {
+    // Message Control Register:
+    //   Bits 10-0: Table size N, encoded as N - 1.
+    //   Bit 14: Function Mask.
+    //   Bit 15: MSI-X Enable.
+    msg_ctl: Le16,
+    // Table BIR (bits 2-0) and Table Offset (bits 31-3). Offset is an 8-byte-aligned byte offset
+    // into the BAR named by BIR.
+    table: Le32,
+    // PBA BIR (bits 2-0) and PBA Offset (bits 31-3), same encoding as `table`.
+    pba: Le32,
+}
+// It is safe to implement DataInit; all members are simple numbers and any value is valid.
+unsafe impl DataInit for MsixCap {}
+
+impl PciCapability for MsixCap {
+    fn bytes(&self) -> &[u8] {
+        self.as_slice()
+    }
+
+    fn id(&self) -> PciCapabilityID {
+        PciCapabilityID::Msix
+    }
+}
+
+impl MsixCap {
+    pub fn new(table_size: u16, table_bar: u8, table_off: u32, pba_bar: u8, pba_off: u32) -> Self {
+        MsixCap {
+            msg_ctl: Le16::from(table_size - 1),
+            table: Le32::from((table_off & !0x7) | table_bar as u32),
+            pba: Le32::from((pba_off & !0x7) | pba_bar as u32),
+        }
+    }
+}
+
+// One MSI-X table entry: a 64-bit message address (split lo/hi for natural alignment), a 32-bit
+// message data value, and a 32-bit vector control word whose bit 0 is the per-vector mask bit.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct MsixTableEntry {
+    msg_addr_lo: Le32,
+    msg_addr_hi: Le32,
+    msg_data: Le32,
+    vector_ctl: Le32,
+}
+// It is safe to implement DataInit; all members are simple numbers and any value is valid.
+unsafe impl DataInit for MsixTableEntry {}
+
+const MSIX_TABLE_ENTRY_MASK_BIT: u32 = 0x1;
+const MSIX_TABLE_ENTRY_SIZE: usize = 16;
+
+/// Software model of a device's MSI-X table and Pending Bit Array, along with the global enable
+/// and function-mask bits carried in the capability's message control register.
+///
+/// The table is kept as raw bytes rather than `Vec<MsixTableEntry>` so that byte-granular BAR
+/// accesses can be copied in and out directly; `MsixTableEntry::from_slice` parses out a single
+/// entry when it's needed whole, e.g. in `trigger`.
+///
+/// `set_msg_ctl` should be called whenever the driver writes the message control register so
+/// `is_vector_masked` reflects it; wiring that call up to config space writes requires a
+/// capability-write hook on `PciConfiguration` that this source tree doesn't have, so it isn't
+/// invoked anywhere yet.
+struct MsixConfig {
+    table: Vec<u8>,
+    pba: Vec<u8>,
+    enabled: bool,
+    function_mask: bool,
+}
+
+impl MsixConfig {
+    fn new(num_vectors: u16) -> Self {
+        MsixConfig {
+            table: vec![0u8; num_vectors as usize * MSIX_TABLE_ENTRY_SIZE],
+            pba: vec![0u8; (num_vectors as usize + 7) / 8],
+            enabled: false,
+            function_mask: false,
+        }
+    }
+
+    fn set_msg_ctl(&mut self, msg_ctl: u16) {
+        self.enabled = msg_ctl & (1 << 15) != 0;
+        self.function_mask = msg_ctl & (1 << 14) != 0;
+    }
+
+    fn entry(&self, vector: u16) -> Option<MsixTableEntry> {
+        let start = vector as usize * MSIX_TABLE_ENTRY_SIZE;
+        let end = start + MSIX_TABLE_ENTRY_SIZE;
+        self.table
+            .get(start..end)
+            .and_then(|bytes| MsixTableEntry::from_slice(bytes))
+            .cloned()
+    }
+
+    fn is_vector_masked(&self, vector: u16) -> bool {
+        if !self.enabled || self.function_mask {
+            return true;
+        }
+        match self.entry(vector) {
+            Some(entry) => entry.vector_ctl.to_native() & MSIX_TABLE_ENTRY_MASK_BIT != 0,
+            None => true,
+        }
+    }
+
+    fn set_pba_bit(&mut self, vector: u16) {
+        if let Some(byte) = self.pba.get_mut(vector as usize / 8) {
+            *byte |= 1 << (vector as usize % 8);
+        }
+    }
+
+    /// Delivers `vector`: if unmasked, writes the table entry's message data to its message
+    /// address; if masked, latches the corresponding bit in the Pending Bit Array instead.
+    fn trigger(&mut self, mem: &GuestMemory, vector: u16) {
+        if self.is_vector_masked(vector) {
+            self.set_pba_bit(vector);
+            return;
+        }
+        if let Some(entry) = self.entry(vector) {
+            let msg_addr = (entry.msg_addr_lo.to_native() as u64)
+                | ((entry.msg_addr_hi.to_native() as u64) << 32);
+            let _ = mem.write_obj_at_addr(entry.msg_data.to_native(), GuestAddress(msg_addr));
+        }
+    }
+
+    fn read_table(&self, offset: u64, data: &mut [u8]) {
+        let offset = offset as usize;
+        let len = data.len().min(self.table.len().saturating_sub(offset));
+        if len > 0 {
+            data[..len].copy_from_slice(&self.table[offset..offset + len]);
+        }
+    }
+
+    fn write_table(&mut self, offset: u64, data: &[u8]) {
+        let offset = offset as usize;
+        let len = data.len().min(self.table.len().saturating_sub(offset));
+        if len > 0 {
+            self.table[offset..offset + len].copy_from_slice(&data[..len]);
+        }
+    }
+
+    // The Pending Bit Array is read-only from the driver's point of view, so writes to it are
+    // ignored.
+    fn read_pba(&self, offset: u64, data: &mut [u8]) {
+        if let Some(&byte) = self.pba.get(offset as usize) {
+            if let Some(out) = data.get_mut(0) {
+                *out = byte;
+            }
+        }
+    }
+}
+
+/// Abstracts how a virtio device signals the guest, so `VirtioDevice::activate` doesn't have to
+/// assume a raw `EventFd` + ISR-status pair is the only way to deliver an interrupt. Shared with
+/// a device's worker threads behind an `Arc`, so every method takes `&self`.
+///
+/// This trait is expected to live alongside `VirtioDevice` and `Queue` in the virtio module root
+/// and be re-exported from there like they are; that module isn't part of this source tree, so
+/// it's defined here next to its one concrete implementation instead.
+pub trait VirtioInterrupt: Send + Sync {
+    /// Signals that `queue`'s used ring has new entries available for the driver.
+    fn signal_used_queue(&self, queue: &Queue) -> Result<()>;
+    /// Signals that the device configuration has changed.
+    fn signal_config_changed(&self) -> Result<()>;
+    /// Returns the raw eventfd backing vector `queue_index`, if this implementation has one a
+    /// vhost-style backend could bind directly into the kernel instead of going through
+    /// `signal_used_queue`.
+    fn notifier(&self, queue_index: usize) -> Option<&EventFd>;
+}
+
+/// The legacy PCI INTx `VirtioInterrupt`: every signal sets the matching bit in the ISR status
+/// register and triggers the device's single legacy interrupt eventfd.
+pub struct VirtioPciInterrupt {
+    interrupt_status: Arc<AtomicUsize>,
+    interrupt_evt: EventFd,
+}
+
+impl VirtioPciInterrupt {
+    pub fn new(interrupt_status: Arc<AtomicUsize>, interrupt_evt: EventFd) -> Self {
+        VirtioPciInterrupt {
+            interrupt_status,
+            interrupt_evt,
+        }
+    }
+}
+
+impl VirtioInterrupt for VirtioPciInterrupt {
+    fn signal_used_queue(&self, _queue: &Queue) -> Result<()> {
+        self.interrupt_status
+            .fetch_or(INTERRUPT_STATUS_USED_RING as usize, Ordering::SeqCst);
+        self.interrupt_evt.write(1)
+    }
+
+    fn signal_config_changed(&self) -> Result<()> {
+        self.interrupt_status
+            .fetch_or(INTERRUPT_STATUS_CONFIG_CHANGED as usize, Ordering::SeqCst);
+        self.interrupt_evt.write(1)
+    }
+
+    fn notifier(&self, _queue_index: usize) -> Option<&EventFd> {
+        Some(&self.interrupt_evt)
+    }
+}
+
+impl VirtioPciInterrupt {
+    /// Re-asserts the legacy INTx line if `interrupt_status` still has bits set. Should be called
+    /// once the resample eventfd paired with `interrupt_evt` at `assign_irq` time becomes
+    /// readable, i.e. once the guest has EOI'd the line. Its only caller is
+    /// `VirtioPciDevice::resample_interrupt`; the poll loop that watches that fd for readability
+    /// isn't part of this source tree, so nothing in it calls `resample_interrupt`.
+    pub fn resample(&self) -> Result<()> {
+        if self.interrupt_status.load(Ordering::SeqCst) != 0 {
+            self.interrupt_evt.write(1)?;
+        }
+        Ok(())
+    }
+}
+
 /// Subclasses for virtio.
 #[allow(dead_code)]
 #[derive(Copy, Clone)]
@@ -127,13 +346,128 @@ const DEVICE_CONFIG_BAR_OFFSET: u64 = 0x2000;
 const DEVICE_CONFIG_SIZE: u64 = 0x1000;
 const NOTIFICATION_BAR_OFFSET: u64 = 0x3000;
 const NOTIFICATION_SIZE: u64 = 0x1000;
-const CAPABILITY_BAR_SIZE: u64 = 0x4000;
+const MSIX_TABLE_BAR_OFFSET: u64 = 0x4000;
+const MSIX_TABLE_BAR_SIZE: u64 = 0x1000;
+const MSIX_PBA_BAR_OFFSET: u64 = 0x5000;
+const MSIX_PBA_BAR_SIZE: u64 = 0x1000;
+const CAPABILITY_BAR_SIZE: u64 = 0x6000;
 
 const NOTIFY_OFF_MULTIPLIER: u32 = 4; // A dword per notifcation address.
 
+// Virtio device type IDs, per the virtio spec's device ID registry. `TYPE_BLOCK` is the only one
+// defined as a named const in this tree since block.rs is the only concrete virtio device present
+// here, but the network ID is included below anyway since it's the canonical example of a device
+// that needs its own PCI class instead of falling back to `PciClassCode::Other`.
+const VIRTIO_ID_NET: u32 = 1;
+
+// Config space register access must be a dword at a time, so round a device's config space length
+// up to a multiple of 4 before advertising it in the `DeviceConfig` capability.
+const DEVICE_CONFIG_ALIGNMENT: u64 = 4;
+
+/// Maps a virtio device type (as returned by `VirtioDevice::device_type()`) to the PCI
+/// class/subclass pair it should be advertised under (so guests and host tools that match devices
+/// by PCI class, e.g. expecting a virtio-net device to show up as a network controller, see the
+/// right thing instead of the generic `PciClassCode::Other`) and the raw, unaligned length of its
+/// config space (so `add_pci_capabilities` can advertise the `DeviceConfig` capability's true size
+/// instead of a blanket `DEVICE_CONFIG_SIZE`, and `read_bar`/`write_bar` can bounds-check accesses
+/// against it). `TYPE_BLOCK`'s length matches `virtio_blk_config`'s 64-byte layout in `block.rs`
+/// (8 + 4 + 4 + 4 + 4 + 8 + 1 + 3 + 2 + 2 + 4 * 5 + 1 + 3); an unrecognized device type falls back
+/// to `PciClassCode::Other` and the full `DEVICE_CONFIG_SIZE` region so its config space doesn't
+/// get clipped.
+///
+/// `VirtioDevice` doesn't have `pci_class()`/`config_size()` methods of its own to query this
+/// with - those would belong in `virtio/mod.rs` next to the trait itself, which isn't part of this
+/// source tree - so both are derived here, in one place, from `device_type()`, which is the one
+/// piece of device-type information this file already has generic access to. Keeping both in a
+/// single match (rather than two separate ones) means a new device type only needs one new arm
+/// here, not two kept in sync by hand.
+///
+/// TODO(virtio-mod): this duplicates information that belongs on the device itself and will go
+/// stale the next time a device type is added here but not taught to whatever `pci_class()`/
+/// `config_size()` defaults real `VirtioDevice` implementors end up getting. Delete this function
+/// (and fold its match arms into per-device trait overrides) once `virtio/mod.rs` lands.
+fn device_pci_info(device_type: u32) -> (PciClassCode, Box<PciSubclass>, u64) {
+    match device_type {
+        VIRTIO_ID_NET => (
+            PciClassCode::NetworkController,
+            Box::new(PciNetworkControllerSubclass::NetworkController),
+            DEVICE_CONFIG_SIZE,
+        ),
+        TYPE_BLOCK => (
+            PciClassCode::MassStorage,
+            Box::new(PciMassStorageSubclass::MassStorage),
+            64,
+        ),
+        _ => (
+            PciClassCode::Other,
+            Box::new(PciVirtioSubclass::NonTransitionalBase),
+            DEVICE_CONFIG_SIZE,
+        ),
+    }
+}
+
+fn device_config_size_for_device_type(device_type: u32) -> u64 {
+    let (_, _, len) = device_pci_info(device_type);
+    (len + DEVICE_CONFIG_ALIGNMENT - 1) / DEVICE_CONFIG_ALIGNMENT * DEVICE_CONFIG_ALIGNMENT
+}
+
+/// Snapshot of a single virtqueue's guest-programmed configuration, as recorded by
+/// `VirtioPciDevice::snapshot`. Doesn't include the ring contents themselves (descriptor/avail/used
+/// entries) - those live in guest memory and come back for free once the VM's memory snapshot is
+/// restored; only the queue size, ring addresses and ready flag the driver programmed through the
+/// common config need to be reapplied.
+#[derive(Clone)]
+pub struct VirtioPciQueueState {
+    pub max_size: u16,
+    pub size: u16,
+    pub ready: bool,
+    pub desc_table: GuestAddress,
+    pub avail_ring: GuestAddress,
+    pub used_ring: GuestAddress,
+}
+
+/// Snapshot of `VirtioPciCommonConfig`'s guest-visible register state.
+#[derive(Clone)]
+pub struct VirtioPciCommonConfigState {
+    pub driver_status: u8,
+    pub config_generation: u8,
+    pub device_feature_select: u32,
+    pub driver_feature_select: u32,
+    pub queue_select: u16,
+}
+
+/// Snapshot of a `VirtioPciDevice`'s transport-level state, suitable for VM save/restore or live
+/// migration. Covers everything the PCI transport itself owns - activation state, the legacy INTx
+/// `interrupt_status` bits, and each queue's common-config-programmed fields.
+///
+/// WARNING: device-specific config (e.g. virtio-blk's `avail_features`/`acked_features`/
+/// `config_space`, captured separately by `Block::snapshot`/`restore`) is deliberately out of
+/// scope here. Capturing it generically would need a `VirtioDevice::save()`/`restore()` hook so
+/// `VirtioPciDevice` could call through `self.device` without knowing its concrete type, but
+/// `VirtioDevice` is defined in `virtio/mod.rs`, and that module isn't part of this source tree.
+/// Callers of `VirtioPciDevice::snapshot`/`restore` MUST separately snapshot/restore the wrapped
+/// `self.device` (by whatever concrete-type-specific means they constructed it with, e.g.
+/// `Block::snapshot`/`restore`) - see `VirtioPciDevice::restore`'s doc comment.
+#[derive(Clone)]
+pub struct VirtioPciDeviceState {
+    pub device_activated: bool,
+    pub interrupt_status: usize,
+    pub queues: Vec<VirtioPciQueueState>,
+    pub common_config: VirtioPciCommonConfigState,
+}
+
 /// Implements the
 /// [PCI](http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-650001)
 /// transport for virtio devices.
+///
+/// Interrupt delivery is INTx-only: legacy level-triggered INTx (see `VirtioPciInterrupt`) is the
+/// only path `write_bar`'s activation logic ever wires up. The MSI-X table/PBA BAR regions and
+/// `MsixConfig` software model exist, and `trigger_msix_vector` can signal through them, but
+/// nothing calls `trigger_msix_vector` anywhere in this tree, `MsixConfig::set_msg_ctl` (the only
+/// way `enabled`/`function_mask` ever change) is never invoked, and the MSI-X PCI capability
+/// itself isn't advertised to the guest (see `add_pci_capabilities`) - so none of that plumbing is
+/// reachable yet. Treat it as scaffolding for a future capability-write hook and MSI-X/INTx
+/// selection, not a working second delivery path.
 pub struct VirtioPciDevice {
     config_regs: PciConfiguration,
 
@@ -142,12 +476,28 @@ pub struct VirtioPciDevice {
 
     interrupt_status: Arc<AtomicUsize>,
     interrupt_evt: Option<EventFd>,
+    // The resample half of the level-triggered INTx pair assigned in `assign_irq`. Kept so
+    // `keep_fds` can report it to survive jailing; nothing in this tree polls it for readability
+    // (see `resample_interrupt`'s doc comment).
+    interrupt_resample_evt: Option<EventFd>,
+    // Handle to the same `VirtioPciInterrupt` handed to `device.activate`, kept so
+    // `resample_interrupt` can re-check `interrupt_status` and re-assert the line.
+    resample_interrupt: Option<Arc<VirtioPciInterrupt>>,
     queues: Vec<Queue>,
     queue_evts: Vec<EventFd>,
     mem: Option<GuestMemory>,
     settings_bar: u8,
+    // True length of `device`'s config space, as determined by `device_config_size_for_device_type`.
+    // Used to size the `DeviceConfig` capability and to bounds-check accesses in `read_bar`/
+    // `write_bar`, instead of assuming the blanket `DEVICE_CONFIG_SIZE` region is fully backed.
+    device_config_size: u64,
 
     common_config: VirtioPciCommonConfig,
+    // One MSI-X vector per queue plus one for device configuration change notifications. Note
+    // that `common_config` doesn't yet have `config_msix_vector`/`queue_msix_vector` fields to
+    // bind a particular queue to a particular vector, since `VirtioPciCommonConfig`'s source
+    // isn't part of this tree; only the table/PBA/capability plumbing lives here so far.
+    msix_config: MsixConfig,
 }
 
 impl VirtioPciDevice {
@@ -164,12 +514,16 @@ impl VirtioPciDevice {
             .collect();
 
         let pci_device_id = 0x1040 + device.device_type() as u16;
+        // One vector per queue plus one for device configuration change notifications.
+        let num_vectors = device.queue_max_sizes().len() as u16 + 1;
+        let (class_code, subclass, _) = device_pci_info(device.device_type());
+        let device_config_size = device_config_size_for_device_type(device.device_type());
 
         let config_regs = PciConfiguration::new(
             0x1af4, // Virtio vendor ID.
             0x1040 + device.device_type() as u16,
-            PciClassCode::Other, // TODO(dgreid)
-            &PciVirtioSubclass::NonTransitionalBase,
+            class_code,
+            &*subclass,
             PciHeaderType::Device,
             0x1af4,
             pci_device_id,
@@ -181,10 +535,13 @@ impl VirtioPciDevice {
             device_activated: false,
             interrupt_status: Arc::new(AtomicUsize::new(0)),
             interrupt_evt: None,
+            interrupt_resample_evt: None,
+            resample_interrupt: None,
             queues,
             queue_evts,
             mem: None,
             settings_bar: 0,
+            device_config_size,
             common_config: VirtioPciCommonConfig {
                 driver_status: 0,
                 config_generation: 0,
@@ -192,6 +549,7 @@ impl VirtioPciDevice {
                 driver_feature_select: 0,
                 queue_select: 0,
             },
+            msix_config: MsixConfig::new(num_vectors),
         })
     }
 
@@ -207,6 +565,132 @@ impl VirtioPciDevice {
         self.interrupt_evt.as_ref()
     }
 
+    /// Re-checks `interrupt_status` and re-asserts the legacy INTx line if work is still pending.
+    /// Call this once `interrupt_resample_evt` becomes readable, i.e. once the guest has EOI'd the
+    /// line; the poll loop that watches that fd for readability isn't part of this source tree.
+    pub fn resample_interrupt(&self) {
+        if let Some(interrupt) = self.resample_interrupt.as_ref() {
+            if let Err(e) = interrupt.resample() {
+                error!("failed to resample interrupt: {:?}", e);
+            }
+        }
+    }
+
+    /// Signals MSI-X `vector`, writing the table entry's message data to its message address if
+    /// the vector is unmasked, or latching the Pending Bit Array bit if it's masked.
+    ///
+    /// Callers must already know which vector a queue or the device configuration change event
+    /// is bound to; `VirtioPciCommonConfig` doesn't carry `queue_msix_vector`/`config_msix_vector`
+    /// in this tree, so that binding - and the resulting choice between this and the legacy INTx
+    /// path - isn't wired up here yet.
+    pub fn trigger_msix_vector(&mut self, vector: u16) {
+        if let Some(mem) = self.mem.as_ref() {
+            self.msix_config.trigger(mem, vector);
+        }
+    }
+
+    /// Captures this device's transport-level state for save/restore or migration. See
+    /// `VirtioPciDeviceState`'s doc comment for what's covered and what isn't.
+    pub fn snapshot(&self) -> VirtioPciDeviceState {
+        VirtioPciDeviceState {
+            device_activated: self.device_activated,
+            interrupt_status: self.interrupt_status.load(Ordering::SeqCst),
+            queues: self
+                .queues
+                .iter()
+                .map(|queue| VirtioPciQueueState {
+                    max_size: queue.max_size,
+                    size: queue.size,
+                    ready: queue.ready,
+                    desc_table: queue.desc_table,
+                    avail_ring: queue.avail_ring,
+                    used_ring: queue.used_ring,
+                })
+                .collect(),
+            common_config: VirtioPciCommonConfigState {
+                driver_status: self.common_config.driver_status,
+                config_generation: self.common_config.config_generation,
+                device_feature_select: self.common_config.device_feature_select,
+                driver_feature_select: self.common_config.driver_feature_select,
+                queue_select: self.common_config.queue_select,
+            },
+        }
+    }
+
+    /// Restores a snapshot taken by `snapshot`. `config_generation` and each queue's addresses are
+    /// written back before the device is (re-)activated, so that if it was activated when
+    /// snapshotted, the guest sees a device that looks exactly as it left it - same generation,
+    /// same ring addresses - rather than one that quietly reset itself.
+    ///
+    /// Expects to be called on a freshly constructed device that has already had `assign_irq`
+    /// called on it, so `interrupt_evt` is still available to rebuild the `VirtioInterrupt` handed
+    /// to `device.activate`.
+    ///
+    /// WARNING: this does NOT restore `self.device`'s own state (see `VirtioPciDeviceState`'s doc
+    /// comment) - the caller is responsible for separately restoring the wrapped device (e.g. via
+    /// `Block::restore`) before or after calling this, or the guest will see a device that has
+    /// reset its feature negotiation and config space.
+    pub fn restore(&mut self, state: VirtioPciDeviceState, mem: GuestMemory) {
+        self.interrupt_status
+            .store(state.interrupt_status, Ordering::SeqCst);
+
+        self.common_config.driver_status = state.common_config.driver_status;
+        self.common_config.config_generation = state.common_config.config_generation;
+        self.common_config.device_feature_select = state.common_config.device_feature_select;
+        self.common_config.driver_feature_select = state.common_config.driver_feature_select;
+        self.common_config.queue_select = state.common_config.queue_select;
+
+        self.queues = state
+            .queues
+            .iter()
+            .map(|saved_queue| {
+                let mut queue = Queue::new(saved_queue.max_size);
+                queue.size = saved_queue.size;
+                queue.ready = saved_queue.ready;
+                queue.desc_table = saved_queue.desc_table;
+                queue.avail_ring = saved_queue.avail_ring;
+                queue.used_ring = saved_queue.used_ring;
+                queue
+            })
+            .collect();
+
+        self.mem = Some(mem.clone());
+        self.device_activated = false;
+
+        if !state.device_activated {
+            return;
+        }
+
+        let interrupt_evt = match self.interrupt_evt.take() {
+            Some(interrupt_evt) => interrupt_evt,
+            None => {
+                error!("cannot restore an activated device without an assigned irq");
+                return;
+            }
+        };
+
+        let mut queue_evts = Vec::new();
+        for _ in self.queues.iter() {
+            match EventFd::new() {
+                Ok(evt) => queue_evts.push(evt),
+                Err(e) => {
+                    error!("failed to create queue eventfd while restoring: {:?}", e);
+                    return;
+                }
+            }
+        }
+
+        let interrupt = Arc::new(VirtioPciInterrupt::new(
+            self.interrupt_status.clone(),
+            interrupt_evt,
+        ));
+        self.resample_interrupt = Some(interrupt.clone());
+        self.device
+            .activate(mem, interrupt, self.queues.clone(), queue_evts.split_off(0));
+        self.queue_evts = queue_evts;
+        self.device_activated = true;
+    }
+
     fn is_driver_ready(&self) -> bool {
         let ready_bits =
             (DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_DRIVER_OK | DEVICE_FEATURES_OK) as u8;
@@ -240,12 +724,11 @@ impl VirtioPciDevice {
         );
         self.config_regs.add_capability(&isr_cap);
 
-        // TODO(dgreid) - set based on device's configuration size?
         let device_cap = VirtioPciCap::new(
             PciCapabilityType::DeviceConfig,
             settings_bar,
             DEVICE_CONFIG_BAR_OFFSET as u32,
-            DEVICE_CONFIG_SIZE as u32,
+            self.device_config_size as u32,
         );
         self.config_regs.add_capability(&device_cap);
 
@@ -267,6 +750,23 @@ impl VirtioPciDevice {
         self.config_regs
             .add_capability(&configuration_cap);
 
+        // Not advertised as a PCI capability yet: nothing in this tree dispatches PCI config space
+        // writes to the capability's Message Control register, so `MsixConfig::set_msg_ctl` (the
+        // only thing that ever sets `enabled`/`function_mask`) can never be called, and
+        // `write_bar`'s activation path always builds the legacy INTx `VirtioPciInterrupt` instead
+        // of ever selecting an MSI-X delivery path. Advertising the capability without that wiring
+        // would offer the guest an MSI-X mode that can structurally never deliver an interrupt.
+        // The table/PBA BAR regions and `MsixConfig` stay in place so the capability-write hook and
+        // `trigger_msix_vector` selection logic have something to land on top of, but the guest
+        // can't discover or enable MSI-X until the capability below is registered alongside them.
+        let _msix_cap = MsixCap::new(
+            (self.msix_config.table.len() / MSIX_TABLE_ENTRY_SIZE) as u16,
+            settings_bar,
+            MSIX_TABLE_BAR_OFFSET as u32,
+            settings_bar,
+            MSIX_PBA_BAR_OFFSET as u32,
+        );
+
         self.settings_bar = settings_bar;
     }
 }
@@ -277,12 +777,16 @@ impl PciDevice for VirtioPciDevice {
         if let Some(ref interrupt_evt) = self.interrupt_evt {
             fds.push(interrupt_evt.as_raw_fd());
         }
+        if let Some(ref interrupt_resample_evt) = self.interrupt_resample_evt {
+            fds.push(interrupt_resample_evt.as_raw_fd());
+        }
         fds
     }
 
-    fn assign_irq(&mut self, irq_evt: EventFd, irq_num: u32, irq_pin: PciInterruptPin) {
+    fn assign_irq(&mut self, irq: IrqLevelEvent, irq_num: u32, irq_pin: PciInterruptPin) {
         self.config_regs.set_irq(irq_num as u8, irq_pin);
-        self.interrupt_evt = Some(irq_evt); // TODO(dverkamp): new - maybe remove init of interrupt_evt in constructor
+        self.interrupt_evt = Some(irq.trigger); // TODO(dverkamp): new - maybe remove init of interrupt_evt in constructor
+        self.interrupt_resample_evt = Some(irq.resample);
     }
 
     fn set_guest_memory(&mut self, mem: GuestMemory) {
@@ -346,7 +850,7 @@ impl PciDevice for VirtioPciDevice {
                 }
             }
             o if DEVICE_CONFIG_BAR_OFFSET <= o
-                && o < DEVICE_CONFIG_BAR_OFFSET + DEVICE_CONFIG_SIZE =>
+                && o < DEVICE_CONFIG_BAR_OFFSET + self.device_config_size =>
             {
                 self.device.read_config(o - DEVICE_CONFIG_BAR_OFFSET, data);
             }
@@ -355,6 +859,12 @@ impl PciDevice for VirtioPciDevice {
             {
                 // Handled with ioeventfds.
             }
+            o if MSIX_TABLE_BAR_OFFSET <= o && o < MSIX_TABLE_BAR_OFFSET + MSIX_TABLE_BAR_SIZE => {
+                self.msix_config.read_table(o - MSIX_TABLE_BAR_OFFSET, data);
+            }
+            o if MSIX_PBA_BAR_OFFSET <= o && o < MSIX_PBA_BAR_OFFSET + MSIX_PBA_BAR_SIZE => {
+                self.msix_config.read_pba(o - MSIX_PBA_BAR_OFFSET, data);
+            }
             _ => (),
         }
     }
@@ -376,7 +886,7 @@ impl PciDevice for VirtioPciDevice {
                 }
             }
             o if DEVICE_CONFIG_BAR_OFFSET <= o
-                && o < DEVICE_CONFIG_BAR_OFFSET + DEVICE_CONFIG_SIZE =>
+                && o < DEVICE_CONFIG_BAR_OFFSET + self.device_config_size =>
             {
                 self.device.write_config(o - DEVICE_CONFIG_BAR_OFFSET, data);
             }
@@ -385,16 +895,29 @@ impl PciDevice for VirtioPciDevice {
             {
                 // TODO(dgreid) - notify the correct virt queue, use eventFD and allocator?
             }
+            o if MSIX_TABLE_BAR_OFFSET <= o && o < MSIX_TABLE_BAR_OFFSET + MSIX_TABLE_BAR_SIZE => {
+                self.msix_config.write_table(o - MSIX_TABLE_BAR_OFFSET, data);
+            }
+            o if MSIX_PBA_BAR_OFFSET <= o && o < MSIX_PBA_BAR_OFFSET + MSIX_PBA_BAR_SIZE => {
+                // The Pending Bit Array is read-only from the driver's point of view.
+            }
             _ => (),
         };
 
         if !self.device_activated && self.is_driver_ready() && self.are_queues_valid() {
             if let Some(interrupt_evt) = self.interrupt_evt.take() {
                 if let Some(mem) = self.mem.take() {
+                    // Legacy INTx is the only delivery path wired up so far; MSI-X would need a
+                    // `VirtioInterrupt` impl that consults `msix_config`/`common_config`'s
+                    // per-queue vector bindings instead (see `trigger_msix_vector`'s doc comment).
+                    let interrupt = Arc::new(VirtioPciInterrupt::new(
+                        self.interrupt_status.clone(),
+                        interrupt_evt,
+                    ));
+                    self.resample_interrupt = Some(interrupt.clone());
                     self.device.activate(
                         mem,
-                        interrupt_evt,
-                        self.interrupt_status.clone(),
+                        interrupt,
                         self.queues.clone(),
                         self.queue_evts.split_off(0),
                     );
@@ -403,4 +926,48 @@ impl PciDevice for VirtioPciDevice {
             }
         }
     }
+
+    fn bar_size(&self, bar_num: usize) -> Option<u64> {
+        if bar_num as u8 == self.settings_bar {
+            Some(CAPABILITY_BAR_SIZE)
+        } else {
+            None
+        }
+    }
+
+    fn move_bar(&mut self, old_base: u64, new_base: u64) -> std::result::Result<(), PciDeviceError> {
+        // `read_bar`/`write_bar` and `ioeventfds` all recompute the current base address from
+        // `config_regs.get_bar_addr(self.settings_bar)` on every access rather than caching it, so
+        // the config space write that moved the BAR is already the only mapping state this device
+        // keeps. There's nothing further to update here; this exists so the device manager has a
+        // point to hang its own ioeventfd/MMIO-bus re-registration off of once it sees the
+        // `BarReprogrammingParams` from `detect_bar_reprogram`, and so a future cached-base
+        // optimization has somewhere to update.
+        let _ = (old_base, new_base);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resample_only_retriggers_with_pending_interrupt_status() {
+        let interrupt_status = Arc::new(AtomicUsize::new(0));
+        let interrupt_evt = EventFd::new().unwrap();
+        let notifier_evt = interrupt_evt.try_clone().unwrap();
+        let interrupt = VirtioPciInterrupt::new(interrupt_status.clone(), interrupt_evt);
+
+        // Nothing pending yet (the guest already drained the used ring by the time it EOI'd):
+        // resample must not write to the eventfd.
+        interrupt.resample().unwrap();
+
+        // Work is still pending: resample re-asserts the line.
+        interrupt_status.fetch_or(INTERRUPT_STATUS_USED_RING as usize, Ordering::SeqCst);
+        interrupt.resample().unwrap();
+
+        // Exactly one write reached the eventfd - the pending-bits call, not the empty one.
+        assert_eq!(notifier_evt.read().unwrap(), 1);
+    }
 }
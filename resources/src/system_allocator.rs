@@ -5,17 +5,112 @@
 use address_allocator::AddressAllocator;
 use sys_util::pagesize;
 
+/// Describes a legacy interrupt controller (e.g. an IOAPIC) that contributes a contiguous range
+/// of GSI pins to the system's interrupt topology.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct GsiApic {
+    base_gsi: u32,
+    num_pins: u32,
+}
+
+impl GsiApic {
+    /// Creates a new `GsiApic` describing `num_pins` legacy pins starting at GSI `base_gsi`.
+    pub fn new(base_gsi: u32, num_pins: u32) -> Self {
+        GsiApic { base_gsi, num_pins }
+    }
+}
+
+/// The number of IDs the GIC architecture reserves for banked PPIs and SGIs below the first
+/// shared peripheral interrupt (SPI).
+const AARCH64_GIC_NR_PPI_SGI: u32 = 32;
+
+/// Selects the interrupt source model `GsiAllocator` draws GSIs from, matching the guest
+/// architecture's interrupt controller topology.
+pub enum IrqRoutingConfig {
+    /// x86_64: legacy GSIs are drawn from one or more IOAPIC pin ranges.
+    Apic(Vec<GsiApic>),
+    /// aarch64: legacy GSIs are drawn from GIC SPIs, reserving `num_legacy_spis` of them above
+    /// the banked PPI/SGI region for `IRQCHIP`-type routing entries.
+    Gic { num_legacy_spis: u32 },
+}
+
+/// Allocates global system interrupt (GSI) numbers.
+///
+/// Legacy GSIs (IOAPIC pins on x86_64, GIC SPIs on aarch64) are drawn from the range described by
+/// the `IrqRoutingConfig` the allocator is seeded with, while dynamically routed MSI/MSI-X GSIs
+/// are drawn from above the highest legacy GSI so the two pools never collide. The
+/// `allocate_irq`/`allocate_gsi` API is identical across arches so device code stays portable.
+#[derive(Debug, Eq, PartialEq)]
+pub struct GsiAllocator {
+    legacy_base: u32,
+    next_irq: u32,
+    max_legacy_gsi: u32,
+    next_gsi: u32,
+}
+
+impl GsiAllocator {
+    /// Creates a new `GsiAllocator` for the given interrupt routing topology.
+    pub fn new(config: IrqRoutingConfig) -> Self {
+        let (legacy_base, max_legacy_gsi) = match config {
+            IrqRoutingConfig::Apic(apics) => {
+                let legacy_base = apics.iter().map(|apic| apic.base_gsi).min().unwrap_or(0);
+                let max_legacy_gsi = apics
+                    .iter()
+                    .map(|apic| apic.base_gsi + apic.num_pins)
+                    .max()
+                    .unwrap_or(0);
+                (legacy_base, max_legacy_gsi)
+            }
+            IrqRoutingConfig::Gic { num_legacy_spis } => (
+                AARCH64_GIC_NR_PPI_SGI,
+                AARCH64_GIC_NR_PPI_SGI + num_legacy_spis,
+            ),
+        };
+        GsiAllocator {
+            legacy_base,
+            next_irq: legacy_base,
+            max_legacy_gsi,
+            next_gsi: max_legacy_gsi,
+        }
+    }
+
+    /// Returns the first legacy GSI handed out by this allocator (the base IOAPIC pin on
+    /// x86_64, or the base SPI above the GIC's PPI/SGI region on aarch64), so callers can build
+    /// `IRQCHIP`-type routing entries for legacy devices.
+    pub fn legacy_base_gsi(&self) -> u32 {
+        self.legacy_base
+    }
+
+    /// Reserves the next available legacy GSI (an IOAPIC pin on x86_64, a GIC SPI on aarch64).
+    pub fn allocate_irq(&mut self) -> Option<u32> {
+        if self.next_irq >= self.max_legacy_gsi {
+            return None;
+        }
+        let irq = self.next_irq;
+        self.next_irq += 1;
+        Some(irq)
+    }
+
+    /// Reserves the next available GSI for dynamic MSI/MSI-X routing, drawn from above the
+    /// highest legacy GSI.
+    pub fn allocate_gsi(&mut self) -> Option<u32> {
+        let gsi = self.next_gsi;
+        self.next_gsi = gsi.checked_add(1)?;
+        Some(gsi)
+    }
+}
+
 /// Manages allocating system resources such as address space and interrupt numbers.
 ///
 /// # Example - Use the `SystemAddress` builder.
 ///
 /// ```
-/// # use sys_util::AddressRanges;
+/// # use resources::{AddressRanges, GsiApic, IrqRoutingConfig};
 ///   if let Some(mut a) = AddressRanges::new()
 ///           .add_io_addresses(0x1000, 0x10000)
 ///           .add_device_addresses(0x10000000, 0x10000000)
 ///           .add_mmio_addresses(0x30000000, 0x10000)
-///           .create_allocator(5) {
+///           .create_allocator(IrqRoutingConfig::Apic(vec![GsiApic::new(5, 24)])) {
 ///       assert_eq!(a.allocate_irq(), Some(5));
 ///       assert_eq!(a.allocate_irq(), Some(6));
 ///       assert_eq!(a.allocate_device_addresses(0x100), Some(0x10000000));
@@ -26,7 +121,8 @@ pub struct SystemAllocator {
     io_address_space: AddressAllocator,
     device_address_space: AddressAllocator,
     mmio_address_space: AddressAllocator,
-    next_irq: u32,
+    platform_mmio_address_space: Option<AddressAllocator>,
+    gsi_allocator: GsiAllocator,
 }
 
 impl SystemAllocator {
@@ -40,29 +136,44 @@ impl SystemAllocator {
     /// * `dev_size` - The size of device memory.
     /// * `mmio_base` - The starting address of MMIO space.
     /// * `mmio_size` - The size of MMIO space.
-    /// * `first_irq` - The first irq number to give out.
+    /// * `platform_mmio_range` - The `(base, size)` of the platform-device MMIO space, if any.
+    /// * `irq_routing` - The arch-specific interrupt source model seeding the GSI allocator.
     fn new(io_base: u64, io_size: u64,
                dev_base: u64, dev_size: u64,
                mmio_base: u64, mmio_size: u64,
-               first_irq: u32)
+               platform_mmio_range: Option<(u64, u64)>,
+               irq_routing: IrqRoutingConfig)
             -> Option<Self> {
         let page_size = pagesize() as u64;
+        let platform_mmio_address_space = match platform_mmio_range {
+            Some((base, size)) => Some(AddressAllocator::new(base, size, Some(page_size))?),
+            None => None,
+        };
         Some(SystemAllocator {
             io_address_space: AddressAllocator::new(io_base, io_size, Some(0x400))?,
             device_address_space: AddressAllocator::new(dev_base, dev_size, Some(page_size))?,
             mmio_address_space: AddressAllocator::new(mmio_base, mmio_size, Some(page_size))?,
-            next_irq: first_irq,
+            platform_mmio_address_space,
+            gsi_allocator: GsiAllocator::new(irq_routing),
         })
     }
 
-    /// Reserves the next available system irq number.
+    /// Reserves the next available legacy irq number (an IOAPIC pin on x86_64, a GIC SPI on
+    /// aarch64).
     pub fn allocate_irq(&mut self) -> Option<u32> {
-        if let Some(irq_num) = self.next_irq.checked_add(1) {
-            self.next_irq = irq_num;
-            Some(irq_num - 1)
-        } else {
-            None
-        }
+        self.gsi_allocator.allocate_irq()
+    }
+
+    /// Reserves the next available GSI for dynamic MSI/MSI-X routing.
+    pub fn allocate_gsi(&mut self) -> Option<u32> {
+        self.gsi_allocator.allocate_gsi()
+    }
+
+    /// Returns the base GSI of the legacy interrupt range (the base IOAPIC pin on x86_64, or the
+    /// base SPI above the GIC's PPI/SGI region on aarch64), for building `IRQCHIP`-type routing
+    /// entries for legacy devices.
+    pub fn legacy_irq_base(&self) -> u32 {
+        self.gsi_allocator.legacy_base_gsi()
     }
 
     /// Reserves a section of `size` bytes of IO address space.
@@ -79,6 +190,85 @@ impl SystemAllocator {
     pub fn allocate_mmio_addresses(&mut self, size: u64) -> Option<u64> {
         self.mmio_address_space.allocate(size)
     }
+
+    /// Reserves a section of `size` bytes of IO address space aligned to `alignment` instead of
+    /// the pool's default alignment.
+    pub fn allocate_io_addresses_with_align(&mut self, size: u64, alignment: u64) -> Option<u64> {
+        self.io_address_space.allocate_with_align(size, alignment)
+    }
+
+    /// Reserves a section of `size` bytes of device address space aligned to `alignment` instead
+    /// of the pool's default alignment.
+    pub fn allocate_device_addresses_with_align(
+        &mut self,
+        size: u64,
+        alignment: u64,
+    ) -> Option<u64> {
+        self.device_address_space.allocate_with_align(size, alignment)
+    }
+
+    /// Reserves a section of `size` bytes of MMIO address space aligned to `alignment` instead
+    /// of the pool's default alignment. Different BARs demand different natural alignments, so
+    /// callers that need something other than the page-sized default should use this instead of
+    /// `allocate_mmio_addresses`.
+    pub fn allocate_mmio_addresses_with_align(&mut self, size: u64, alignment: u64) -> Option<u64> {
+        self.mmio_address_space.allocate_with_align(size, alignment)
+    }
+
+    /// Reserves the exact IO address range `[addr, addr + size)`. Returns `None` if the range
+    /// overlaps an existing allocation or falls outside the managed IO address space.
+    pub fn allocate_io_addresses_at(&mut self, addr: u64, size: u64) -> Option<u64> {
+        self.io_address_space.allocate_at(addr, size)
+    }
+
+    /// Reserves the exact device address range `[addr, addr + size)`. Returns `None` if the
+    /// range overlaps an existing allocation or falls outside the managed device address space.
+    pub fn allocate_device_addresses_at(&mut self, addr: u64, size: u64) -> Option<u64> {
+        self.device_address_space.allocate_at(addr, size)
+    }
+
+    /// Reserves the exact MMIO address range `[addr, addr + size)`. Returns `None` if the range
+    /// overlaps an existing allocation or falls outside the managed MMIO address space.
+    pub fn allocate_mmio_addresses_at(&mut self, addr: u64, size: u64) -> Option<u64> {
+        self.mmio_address_space.allocate_at(addr, size)
+    }
+
+    /// Releases a section of IO address space previously returned by `allocate_io_addresses`,
+    /// returning it to the free pool so it can be reused by a later allocation.
+    pub fn free_io_addresses(&mut self, base: u64, size: u64) {
+        self.io_address_space.free(base, size);
+    }
+
+    /// Releases a section of device address space previously returned by
+    /// `allocate_device_addresses`, returning it to the free pool so it can be reused by a later
+    /// allocation.
+    pub fn free_device_addresses(&mut self, base: u64, size: u64) {
+        self.device_address_space.free(base, size);
+    }
+
+    /// Releases a section of MMIO address space previously returned by `allocate_mmio_addresses`,
+    /// returning it to the free pool so it can be reused by a later allocation.
+    pub fn free_mmio_addresses(&mut self, base: u64, size: u64) {
+        self.mmio_address_space.free(base, size);
+    }
+
+    /// Reserves a section of `size` bytes of the 64-bit platform-device MMIO address space, used
+    /// for ACPI control devices and other platform devices that must not overlap the PCI MMIO
+    /// aperture. Returns `None` if no platform MMIO range was configured.
+    pub fn allocate_platform_mmio_addresses(&mut self, size: u64) -> Option<u64> {
+        self.platform_mmio_address_space
+            .as_mut()?
+            .allocate(size)
+    }
+
+    /// Releases a section of platform-device MMIO address space previously returned by
+    /// `allocate_platform_mmio_addresses`, returning it to the free pool so it can be reused by a
+    /// later allocation.
+    pub fn free_platform_mmio_addresses(&mut self, base: u64, size: u64) {
+        if let Some(platform_mmio_address_space) = self.platform_mmio_address_space.as_mut() {
+            platform_mmio_address_space.free(base, size);
+        }
+    }
 }
 
 /// Used to build a system address map for use in creating a `SystemAllocator`.
@@ -89,6 +279,8 @@ pub struct AddressRanges {
     mmio_size: Option<u64>,
     device_base: Option<u64>,
     device_size: Option<u64>,
+    platform_mmio_base: Option<u64>,
+    platform_mmio_size: Option<u64>,
 }
 
 impl AddressRanges {
@@ -100,6 +292,8 @@ impl AddressRanges {
             mmio_size: None,
             device_base: None,
             device_size: None,
+            platform_mmio_base: None,
+            platform_mmio_size: None,
         }
     }
 
@@ -121,10 +315,104 @@ impl AddressRanges {
         self
     }
 
-    pub fn create_allocator(&self, first_irq: u32) -> Option<SystemAllocator> {
+    /// Adds a dedicated 64-bit MMIO window for ACPI control devices and other platform devices,
+    /// guaranteed not to overlap the PCI MMIO aperture added by `add_mmio_addresses`:
+    /// `create_allocator` returns `None` if the two ranges overlap.
+    pub fn add_platform_mmio_addresses(mut self, base: u64, size: u64) -> Self {
+        self.platform_mmio_base = Some(base);
+        self.platform_mmio_size = Some(size);
+        self
+    }
+
+    pub fn create_allocator(&self, irq_routing: IrqRoutingConfig) -> Option<SystemAllocator> {
+        let platform_mmio_range = match (self.platform_mmio_base, self.platform_mmio_size) {
+            (Some(base), Some(size)) => Some((base, size)),
+            _ => None,
+        };
+        if let Some((base, size)) = platform_mmio_range {
+            let (mmio_base, mmio_size) = (self.mmio_base?, self.mmio_size?);
+            if base < mmio_base.saturating_add(mmio_size) && mmio_base < base.saturating_add(size) {
+                return None;
+            }
+        }
         SystemAllocator::new(self.io_base?, self.io_size?,
                              self.device_base?, self.device_size?,
                              self.mmio_base?, self.mmio_size?,
-                             first_irq)
+                             platform_mmio_range,
+                             irq_routing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn basic_allocator() -> SystemAllocator {
+        AddressRanges::new()
+            .add_io_addresses(0x1000, 0x10000)
+            .add_device_addresses(0x10000000, 0x10000000)
+            .add_mmio_addresses(0x30000000, 0x10000)
+            .add_platform_mmio_addresses(0x40000000, 0x10000)
+            .create_allocator(IrqRoutingConfig::Apic(vec![GsiApic::new(5, 2)]))
+            .unwrap()
+    }
+
+    #[test]
+    fn mmio_allocate_with_align_exactly_aligned_start_and_size() {
+        let mut a = basic_allocator();
+
+        // Both the start of the pool and the requested size are already multiples of the
+        // requested alignment, so the allocator must not round either one up and must hand back
+        // two back-to-back, non-overlapping ranges.
+        let first = a.allocate_mmio_addresses_with_align(0x1000, 0x1000).unwrap();
+        assert_eq!(first, 0x30000000);
+
+        let second = a.allocate_mmio_addresses_with_align(0x1000, 0x1000).unwrap();
+        assert_eq!(second, first + 0x1000);
+    }
+
+    #[test]
+    fn gsi_allocator_legacy_irqs_exhaust_before_dynamic_gsis_begin() {
+        let mut a = basic_allocator();
+
+        assert_eq!(a.legacy_irq_base(), 5);
+        assert_eq!(a.allocate_irq(), Some(5));
+        assert_eq!(a.allocate_irq(), Some(6));
+        // The Apic range only covers 2 pins (5 and 6), so a third legacy irq is out of range.
+        assert_eq!(a.allocate_irq(), None);
+
+        // Dynamic GSIs are drawn from above the highest legacy GSI (5 + 2 == 7), never
+        // colliding with the legacy range above.
+        assert_eq!(a.allocate_gsi(), Some(7));
+        assert_eq!(a.allocate_gsi(), Some(8));
+    }
+
+    #[test]
+    fn mmio_allocate_at_fixed_address_then_free_allows_reuse() {
+        let mut a = basic_allocator();
+
+        assert_eq!(a.allocate_mmio_addresses_at(0x30000000, 0x1000), Some(0x30000000));
+        // The range is already taken, so a second fixed allocation overlapping it must fail.
+        assert_eq!(a.allocate_mmio_addresses_at(0x30000000, 0x1000), None);
+
+        a.free_mmio_addresses(0x30000000, 0x1000);
+        // Freeing returns the range to the pool, so the same fixed address can be reused.
+        assert_eq!(a.allocate_mmio_addresses_at(0x30000000, 0x1000), Some(0x30000000));
+    }
+
+    #[test]
+    fn platform_mmio_addresses_are_independent_of_the_pci_mmio_pool() {
+        let mut a = basic_allocator();
+
+        let platform = a.allocate_platform_mmio_addresses(0x1000).unwrap();
+        assert_eq!(platform, 0x40000000);
+
+        // The platform MMIO window and the PCI MMIO window were configured with disjoint base
+        // addresses, so allocations from one must never land inside the other.
+        let pci = a.allocate_mmio_addresses(0x1000).unwrap();
+        assert_eq!(pci, 0x30000000);
+
+        a.free_platform_mmio_addresses(platform, 0x1000);
+        assert_eq!(a.allocate_platform_mmio_addresses(0x1000), Some(0x40000000));
     }
 }
\ No newline at end of file
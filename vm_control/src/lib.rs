@@ -22,6 +22,7 @@ use std::io::{Seek, SeekFrom};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::UnixDatagram;
 use std::result;
+use std::sync::{Condvar, Mutex};
 
 use libc::{ERANGE, EINVAL, ENODEV};
 
@@ -32,6 +33,12 @@ use sys_util::{EventFd, Result, Error as SysError, MmapError, MemoryMapping, Scm
 use resources::{GpuMemoryDesc, GpuMemoryPlaneDesc, SystemAllocator};
 use kvm::{IoeventAddress, Vm};
 
+/// The version of the VM control wire protocol implemented by this crate. Bumped whenever the
+/// fixed header or a struct layout changes in a way that isn't backwards compatible; a peer
+/// advertising a different version is rejected in `recv` rather than risk misinterpreting its
+/// message.
+const VM_CONTROL_PROTOCOL_VERSION: u32 = 1;
+
 #[derive(Debug, PartialEq)]
 /// An error during a request or response transaction.
 pub enum VmControlError {
@@ -46,6 +53,13 @@ pub enum VmControlError {
     BadSize(usize),
     /// There was no associated file descriptor received for a request that expected it.
     ExpectFd,
+    /// The peer's protocol version, carried in the message header, doesn't match ours.
+    VersionMismatch(u32),
+    /// The request or response type is absent from the peer capability bitmask passed to `send`
+    /// (for requests, the `supported_types` a peer advertises in `VmResponse::Hello`), so it
+    /// wasn't sent. The inner value is the `VM_REQUEST_TYPE_*` or `VM_RESPONSE_TYPE_*` that was
+    /// rejected.
+    UnsupportedRequest(u32),
 }
 
 pub type VmControlResult<T> = result::Result<T, VmControlError>;
@@ -67,14 +81,92 @@ impl AsRawFd for MaybeOwnedFd {
     }
 }
 
+/// Shared state that lets `VmRequest::Suspend`/`Resume` park and unpark the VM's vCPU run loops.
+/// Each vCPU thread should call `park_if_paused` once per run-loop iteration (e.g. right after
+/// handling a VM exit); it blocks there for as long as the VM is paused and returns once resumed.
+#[derive(Default)]
+pub struct VcpuControl {
+    paused: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl VcpuControl {
+    pub fn new() -> VcpuControl {
+        VcpuControl {
+            paused: Mutex::new(false),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Blocks the calling thread while the VM is paused, returning once it is resumed. Intended
+    /// to be called from a vCPU run loop.
+    pub fn park_if_paused(&self) {
+        let mut paused = self.paused.lock().unwrap();
+        while *paused {
+            paused = self.cond.wait(paused).unwrap();
+        }
+    }
+
+    /// Pauses the VM: subsequent and already-blocked `park_if_paused` calls will block until
+    /// `resume` is called.
+    pub fn pause(&self) {
+        *self.paused.lock().unwrap() = true;
+    }
+
+    /// Resumes the VM, waking any vCPU threads parked in `park_if_paused`.
+    pub fn resume(&self) {
+        *self.paused.lock().unwrap() = false;
+        self.cond.notify_all();
+    }
+
+    /// True if the VM is currently paused.
+    pub fn is_paused(&self) -> bool {
+        *self.paused.lock().unwrap()
+    }
+}
+
+/// Hook the main VM process implements to wire a virtio-block device onto its PCI/MMIO bus in
+/// response to `VmRequest::AttachDisk`/`DetachDisk`. `vm_control` only owns the request/response
+/// wire protocol; actually building and hooking up the device requires access to the bus and IRQ
+/// routing state that this crate doesn't own.
+pub trait DiskHotplugHandler {
+    /// Attaches a virtio-block device backed by `raw_fd` to the bus and returns the slot number
+    /// to associate with it for a later `detach_disk` call.
+    fn attach_disk(&mut self, raw_fd: RawFd, read_only: bool) -> Result<u32>;
+    /// Detaches the virtio-block device previously assigned `slot` by `attach_disk`.
+    fn detach_disk(&mut self, slot: u32) -> Result<()>;
+}
+
+/// Hook the main VM process implements to bring vCPUs online and offline in response to
+/// `VmRequest::CpuAdd`/`CpuRemove`. `vm_control` only owns the request/response wire protocol;
+/// actually creating and starting a vCPU thread and its run loop requires access to the `Vm` and
+/// the VM's vCPU bookkeeping that this crate doesn't own.
+pub trait VcpuHotplugHandler {
+    /// Creates, starts, and notifies the guest of a new vCPU, returning its cpu id. Should fail
+    /// if the VM's configured maximum vCPU count is already reached.
+    fn add_vcpu(&mut self) -> Result<u32>;
+    /// Stops and tears down the vCPU previously assigned `cpu_id` by `add_vcpu`.
+    fn remove_vcpu(&mut self, cpu_id: u32) -> Result<()>;
+}
+
 /// A request to the main process to perform some operation on the VM.
 ///
 /// Unless otherwise noted, each request should expect a `VmResponse::Ok` to be received on success.
 pub enum VmRequest {
+    /// Exchange protocol capabilities with the other end. Intended to be the first request sent
+    /// over a newly connected socket. The response variant is `VmResponse::Hello`.
+    Hello,
     /// Try to grow or shrink the VM's balloon.
     BalloonAdjust(i32),
+    /// Query the balloon device's current size and virtio-balloon stats. The response variant is
+    /// `VmResponse::BalloonStats`.
+    BalloonStats,
     /// Break the VM's run loop and exit.
     Exit,
+    /// Pause all of the VM's vCPUs until a `Resume` request is received.
+    Suspend,
+    /// Resume a VM previously paused by a `Suspend` request.
+    Resume,
     /// Register the given ioevent address along with given datamatch to trigger the `EventFd`.
     RegisterIoevent(EventFd, IoeventAddress, u32),
     /// Register the given IRQ number to be triggered when the `EventFd` is triggered.
@@ -84,9 +176,19 @@ pub enum VmRequest {
     RegisterMemory(MaybeOwnedFd, usize),
     /// Unregister the given memory slot that was previously registereed with `RegisterMemory`.
     UnregisterMemory(u32),
-    /// Allocate GPU buffer of a given size/format and register the memory into guest address space.
-    /// The response variant is `VmResponse::AllocateAndRegisterGpuMemory`
-    AllocateAndRegisterGpuMemory { width: u32, height: u32, format: u32 },
+    /// Allocate a GPU buffer of a given size and DRM fourcc `format`, and register the memory into
+    /// guest address space. The DRM format `modifier` is not passed to the allocator; it is echoed
+    /// back in the response. The response variant is `VmResponse::AllocateAndRegisterGpuMemory`.
+    AllocateAndRegisterGpuMemory { width: u32, height: u32, format: u32, modifier: u64 },
+    /// Attach a virtio-block device backed by the given fd to the VM's bus. The response variant
+    /// is `VmResponse::AttachDisk`.
+    AttachDisk(MaybeOwnedFd, bool),
+    /// Detach the virtio-block device previously attached at `slot` by an `AttachDisk` request.
+    DetachDisk(u32),
+    /// Bring a new vCPU online. Fails if the VM's configured maximum vCPU count is reached.
+    CpuAdd,
+    /// Take the vCPU with the given cpu id offline.
+    CpuRemove { cpu_id: u32 },
 }
 
 const VM_REQUEST_TYPE_EXIT: u32 = 1;
@@ -94,11 +196,39 @@ const VM_REQUEST_TYPE_REGISTER_MEMORY: u32 = 2;
 const VM_REQUEST_TYPE_UNREGISTER_MEMORY: u32 = 3;
 const VM_REQUEST_TYPE_BALLOON_ADJUST: u32 = 4;
 const VM_REQUEST_TYPE_ALLOCATE_AND_REGISTER_GPU_MEMORY: u32 = 5;
-const VM_REQUEST_SIZE: usize = 32;
+const VM_REQUEST_TYPE_SUSPEND: u32 = 6;
+const VM_REQUEST_TYPE_RESUME: u32 = 7;
+const VM_REQUEST_TYPE_ATTACH_DISK: u32 = 8;
+const VM_REQUEST_TYPE_DETACH_DISK: u32 = 9;
+const VM_REQUEST_TYPE_BALLOON_STATS: u32 = 10;
+const VM_REQUEST_TYPE_HELLO: u32 = 11;
+const VM_REQUEST_TYPE_CPU_ADD: u32 = 12;
+const VM_REQUEST_TYPE_CPU_REMOVE: u32 = 13;
+const VM_REQUEST_SIZE: usize = 64;
+
+// Bitmask of every `VM_REQUEST_TYPE_*` this crate understands, keyed by the same bit positions as
+// the type values themselves. Advertised in `VmResponse::Hello` so a peer can tell ahead of time
+// which request variants are safe to send.
+const VM_REQUEST_TYPES_SUPPORTED: u64 = 1 << VM_REQUEST_TYPE_EXIT
+    | 1 << VM_REQUEST_TYPE_REGISTER_MEMORY
+    | 1 << VM_REQUEST_TYPE_UNREGISTER_MEMORY
+    | 1 << VM_REQUEST_TYPE_BALLOON_ADJUST
+    | 1 << VM_REQUEST_TYPE_ALLOCATE_AND_REGISTER_GPU_MEMORY
+    | 1 << VM_REQUEST_TYPE_SUSPEND
+    | 1 << VM_REQUEST_TYPE_RESUME
+    | 1 << VM_REQUEST_TYPE_ATTACH_DISK
+    | 1 << VM_REQUEST_TYPE_DETACH_DISK
+    | 1 << VM_REQUEST_TYPE_BALLOON_STATS
+    | 1 << VM_REQUEST_TYPE_HELLO
+    | 1 << VM_REQUEST_TYPE_CPU_ADD
+    | 1 << VM_REQUEST_TYPE_CPU_REMOVE;
 
 #[repr(C)]
 #[derive(Clone, Copy, Default)]
 struct VmRequestStruct {
+    request_id: Le64,
+    version: Le32,
+    _padding0: Le32,
     type_: Le32,
     slot: Le32,
     size: Le64,
@@ -106,11 +236,66 @@ struct VmRequestStruct {
     width: Le32,
     height: Le32,
     format: Le32,
+    read_only: Le32,
+    cpu_id: Le32,
+    modifier: Le64,
 }
 
 // Safe because it only has data and has no implicit padding.
 unsafe impl DataInit for VmRequestStruct {}
 
+// Commands sent over `balloon_host_socket` to the balloon device's control thread. Each message
+// is this one-byte tag, followed by the command's payload (if any).
+const BALLOON_CMD_ADJUST: u8 = 1;
+const BALLOON_CMD_STATS: u8 = 2;
+
+// Reply to a `BALLOON_CMD_STATS` query, mirroring the subset of virtio-balloon stats
+// (VIRTIO_BALLOON_S_*) a host controller needs to size the balloon based on real guest memory
+// demand.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct BalloonStatsStruct {
+    actual_pages: Le64,
+    available_pages: Le64,
+    free_pages: Le64,
+    swap_in_pages: Le64,
+    swap_out_pages: Le64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for BalloonStatsStruct {}
+
+const BALLOON_STATS_SIZE: usize = 40;
+
+// Builds a DRM fourcc code the same way the kernel's `DRM_FORMAT_*` constants are defined: four
+// ASCII characters packed little-endian into a u32.
+const fn drm_fourcc(a: u8, b: u8, c: u8, d: u8) -> u32 {
+    (a as u32) | (b as u32) << 8 | (c as u32) << 16 | (d as u32) << 24
+}
+
+const DRM_FORMAT_XRGB8888: u32 = drm_fourcc(b'X', b'R', b'2', b'4');
+const DRM_FORMAT_ARGB8888: u32 = drm_fourcc(b'A', b'R', b'2', b'4');
+const DRM_FORMAT_NV12: u32 = drm_fourcc(b'N', b'V', b'1', b'2');
+const DRM_FORMAT_YUV420: u32 = drm_fourcc(b'Y', b'U', b'1', b'2');
+
+/// Returns how many planes `GpuMemoryDesc::planes` should have populated (non-zero stride) for
+/// `format`, or `None` if `format` isn't one of the fourccs this crate knows the plane layout of.
+/// The `modifier` is accepted for forward compatibility with tiled/compressed layouts that may
+/// someday need a different plane count than their linear counterpart, but no modifier known
+/// today changes a plane count, so it's currently unused beyond that.
+fn gpu_format_plane_count(format: u32, _modifier: u64) -> Option<u32> {
+    match format {
+        DRM_FORMAT_XRGB8888 | DRM_FORMAT_ARGB8888 => Some(1),
+        DRM_FORMAT_NV12 => Some(2),
+        DRM_FORMAT_YUV420 => Some(3),
+        _ => None,
+    }
+}
+
+/// Max number of separate dma-buf FDs a single `VmResponse::AllocateAndRegisterGpuMemory` can
+/// carry. One per `GpuMemoryDesc` plane is the most a multi-planar format needs.
+const MAX_GPU_MEMORY_FDS: usize = 3;
+
 fn register_memory(vm: &mut Vm, allocator: &mut SystemAllocator,
                    fd: &AsRawFd, size: usize) -> Result<(u64, u32)> {
     let mmap = match MemoryMapping::from_fd(fd, size) {
@@ -132,10 +317,11 @@ fn register_memory(vm: &mut Vm, allocator: &mut SystemAllocator,
 }
 
 impl VmRequest {
-    /// Receive a `VmRequest` from the given socket.
+    /// Receive a `VmRequest` from the given socket, along with the request id its sender chose.
     ///
-    /// A `VmResponse` should be sent out over the given socket before another request is received.
-    pub fn recv(s: &UnixDatagram) -> VmControlResult<VmRequest> {
+    /// A `VmResponse` echoing that request id should be sent out over the given socket before
+    /// another request is received.
+    pub fn recv(s: &UnixDatagram) -> VmControlResult<(VmRequest, u64)> {
         assert_eq!(VM_REQUEST_SIZE, std::mem::size_of::<VmRequestStruct>());
         let mut buf = [0; VM_REQUEST_SIZE];
         let (read, file) = s.recv_with_fd(&mut buf)
@@ -147,7 +333,12 @@ impl VmRequest {
         // enough for a VmRequestStruct.
         let req: VmRequestStruct = buf.as_mut().get_ref(0).unwrap().load();
 
-        match req.type_.into() {
+        if req.version.to_native() != VM_CONTROL_PROTOCOL_VERSION {
+            return Err(VmControlError::VersionMismatch(req.version.to_native()));
+        }
+        let request_id = req.request_id.to_native();
+
+        let request = match req.type_.into() {
             VM_REQUEST_TYPE_EXIT => Ok(VmRequest::Exit),
             VM_REQUEST_TYPE_REGISTER_MEMORY => {
                 let fd = file.ok_or(VmControlError::ExpectFd)?;
@@ -161,24 +352,51 @@ impl VmRequest {
             VM_REQUEST_TYPE_ALLOCATE_AND_REGISTER_GPU_MEMORY => {
                 Ok(VmRequest::AllocateAndRegisterGpuMemory { width: req.width.to_native(),
                                                              height: req.height.to_native(),
-                                                             format: req.format.to_native()
+                                                             format: req.format.to_native(),
+                                                             modifier: req.modifier.to_native(),
                     })
             },
+            VM_REQUEST_TYPE_SUSPEND => Ok(VmRequest::Suspend),
+            VM_REQUEST_TYPE_RESUME => Ok(VmRequest::Resume),
+            VM_REQUEST_TYPE_ATTACH_DISK => {
+                let fd = file.ok_or(VmControlError::ExpectFd)?;
+                Ok(VmRequest::AttachDisk(MaybeOwnedFd::Owned(fd),
+                                         req.read_only.to_native() != 0))
+            }
+            VM_REQUEST_TYPE_DETACH_DISK => Ok(VmRequest::DetachDisk(req.slot.into())),
+            VM_REQUEST_TYPE_BALLOON_STATS => Ok(VmRequest::BalloonStats),
+            VM_REQUEST_TYPE_HELLO => Ok(VmRequest::Hello),
+            VM_REQUEST_TYPE_CPU_ADD => Ok(VmRequest::CpuAdd),
+            VM_REQUEST_TYPE_CPU_REMOVE => Ok(VmRequest::CpuRemove { cpu_id: req.cpu_id.into() }),
             _ => Err(VmControlError::InvalidType),
-        }
+        }?;
+        Ok((request, request_id))
     }
 
-    /// Send a `VmRequest` over the given socket.
+    /// Send a `VmRequest` over the given socket, tagged with `request_id` so the matching
+    /// `VmResponse` can be identified.
+    ///
+    /// `peer_capabilities` is the `supported_types` bitmask the peer advertised in a prior
+    /// `VmResponse::Hello`, if any. When present, a request type absent from it is rejected with
+    /// `VmControlError::UnsupportedRequest` rather than sent, since the peer has already told us
+    /// it wouldn't understand it. Pass `None` before a `Hello` exchange has taken place.
     ///
     /// After this request is a sent, a `VmResponse` should be received before sending another
     /// request.
-    pub fn send(&self, s: &UnixDatagram) -> VmControlResult<()> {
+    pub fn send(&self, request_id: u64, peer_capabilities: Option<u64>,
+               s: &UnixDatagram) -> VmControlResult<()> {
         assert_eq!(VM_REQUEST_SIZE, std::mem::size_of::<VmRequestStruct>());
         let mut req = VmRequestStruct::default();
+        req.request_id = Le64::from(request_id);
+        req.version = Le32::from(VM_CONTROL_PROTOCOL_VERSION);
         let mut fd_buf = [0; 1];
         let mut fd_len = 0;
         match self {
+            &VmRequest::Hello => req.type_ = Le32::from(VM_REQUEST_TYPE_HELLO),
             &VmRequest::Exit => req.type_ = Le32::from(VM_REQUEST_TYPE_EXIT),
+            &VmRequest::BalloonStats => req.type_ = Le32::from(VM_REQUEST_TYPE_BALLOON_STATS),
+            &VmRequest::Suspend => req.type_ = Le32::from(VM_REQUEST_TYPE_SUSPEND),
+            &VmRequest::Resume => req.type_ = Le32::from(VM_REQUEST_TYPE_RESUME),
             &VmRequest::RegisterMemory(ref fd, size) => {
                 req.type_ = Le32::from(VM_REQUEST_TYPE_REGISTER_MEMORY);
                 req.size = Le64::from(size as u64);
@@ -193,14 +411,35 @@ impl VmRequest {
                 req.type_ = Le32::from(VM_REQUEST_TYPE_BALLOON_ADJUST);
                 req.num_pages = Le32::from(pages as u32);
             },
-            &VmRequest::AllocateAndRegisterGpuMemory { width, height, format } => {
+            &VmRequest::AllocateAndRegisterGpuMemory { width, height, format, modifier } => {
                 req.type_ = Le32::from(VM_REQUEST_TYPE_ALLOCATE_AND_REGISTER_GPU_MEMORY);
                 req.width = Le32::from(width as u32);
                 req.height = Le32::from(height as u32);
                 req.format = Le32::from(format as u32);
+                req.modifier = Le64::from(modifier);
             },
+            &VmRequest::AttachDisk(ref fd, read_only) => {
+                req.type_ = Le32::from(VM_REQUEST_TYPE_ATTACH_DISK);
+                req.read_only = Le32::from(read_only as u32);
+                fd_buf[0] = fd.as_raw_fd();
+                fd_len = 1;
+            }
+            &VmRequest::DetachDisk(slot) => {
+                req.type_ = Le32::from(VM_REQUEST_TYPE_DETACH_DISK);
+                req.slot = Le32::from(slot);
+            }
+            &VmRequest::CpuAdd => req.type_ = Le32::from(VM_REQUEST_TYPE_CPU_ADD),
+            &VmRequest::CpuRemove { cpu_id } => {
+                req.type_ = Le32::from(VM_REQUEST_TYPE_CPU_REMOVE);
+                req.cpu_id = Le32::from(cpu_id);
+            }
             _ => return Err(VmControlError::InvalidType),
         }
+        if let Some(supported) = peer_capabilities {
+            if supported & (1 << req.type_.to_native()) == 0 {
+                return Err(VmControlError::UnsupportedRequest(req.type_.to_native()));
+            }
+        }
         let mut buf = [0; VM_REQUEST_SIZE];
         buf.as_mut().get_ref(0).unwrap().store(req);
         s.send_with_fds(buf.as_ref(), &fd_buf[..fd_len])
@@ -214,18 +453,35 @@ impl VmRequest {
     /// * `vm` - The `Vm` to perform the request on.
     /// * `allocator` - Used to allocate addresses.
     /// * `running` - Out argument that is set to false if the request was to stop running the VM.
+    /// * `vcpu_control` - Used to pause and resume the VM's vCPU run loops.
+    /// * `disk_hotplug` - Used to attach and detach virtio-block devices from the bus.
+    /// * `vcpu_hotplug` - Used to bring vCPUs online and offline.
     ///
     /// This does not return a result, instead encapsulating the success or failure in a
     /// `VmResponse` with the intended purpose of sending the response back over the  socket that
     /// received this `VmRequest`.
     pub fn execute(&self, vm: &mut Vm, sys_allocator: &mut SystemAllocator, running: &mut bool,
-                   balloon_host_socket: &UnixDatagram) -> VmResponse {
+                   balloon_host_socket: &UnixDatagram, vcpu_control: &VcpuControl,
+                   disk_hotplug: &mut DiskHotplugHandler,
+                   vcpu_hotplug: &mut VcpuHotplugHandler) -> VmResponse {
         *running = true;
         match self {
+            &VmRequest::Hello => VmResponse::Hello {
+                version: VM_CONTROL_PROTOCOL_VERSION,
+                supported_types: VM_REQUEST_TYPES_SUPPORTED,
+            },
             &VmRequest::Exit => {
                 *running = false;
                 VmResponse::Ok
             }
+            &VmRequest::Suspend => {
+                vcpu_control.pause();
+                VmResponse::Ok
+            }
+            &VmRequest::Resume => {
+                vcpu_control.resume();
+                VmResponse::Ok
+            }
             &VmRequest::RegisterIoevent(ref evt, addr, datamatch) => {
                 match vm.register_ioevent(evt, addr, datamatch) {
                     Ok(_) => VmResponse::Ok,
@@ -251,15 +507,37 @@ impl VmRequest {
                 }
             }
             &VmRequest::BalloonAdjust(num_pages) => {
-                let mut buf = [0u8; 4];
-                // write_i32 can't fail as the buffer is 4 bytes long.
-                (&mut buf[0..]).write_i32::<LittleEndian>(num_pages).unwrap();
+                let mut buf = [0u8; 5];
+                buf[0] = BALLOON_CMD_ADJUST;
+                // write_i32 can't fail as the slice is 4 bytes long.
+                (&mut buf[1..]).write_i32::<LittleEndian>(num_pages).unwrap();
                 match balloon_host_socket.send(&buf) {
                     Ok(_) => VmResponse::Ok,
                     Err(_) => VmResponse::Err(SysError::last()),
                 }
             }
-            &VmRequest::AllocateAndRegisterGpuMemory {width, height, format} => {
+            &VmRequest::BalloonStats => {
+                if let Err(_) = balloon_host_socket.send(&[BALLOON_CMD_STATS]) {
+                    return VmResponse::Err(SysError::last());
+                }
+                let mut buf = [0u8; BALLOON_STATS_SIZE];
+                let read = match balloon_host_socket.recv(&mut buf) {
+                    Ok(v) => v,
+                    Err(_) => return VmResponse::Err(SysError::last()),
+                };
+                if read != BALLOON_STATS_SIZE {
+                    return VmResponse::Err(SysError::new(EINVAL));
+                }
+                let stats: BalloonStatsStruct = buf.as_mut().get_ref(0).unwrap().load();
+                VmResponse::BalloonStats {
+                    actual_pages: stats.actual_pages.into(),
+                    available_pages: stats.available_pages.into(),
+                    free_pages: stats.free_pages.into(),
+                    swap_in_pages: stats.swap_in_pages.into(),
+                    swap_out_pages: stats.swap_out_pages.into(),
+                }
+            }
+            &VmRequest::AllocateAndRegisterGpuMemory {width, height, format, modifier} => {
                 let (mut fd, desc) = match sys_allocator.gpu_memory_allocator() {
                     Some(gpu_allocator) => {
                         match gpu_allocator.allocate(width, height, format) {
@@ -269,6 +547,13 @@ impl VmRequest {
                     }
                     None => return VmResponse::Err(SysError::new(ENODEV)),
                 };
+                if let Some(expected_planes) = gpu_format_plane_count(format, modifier) {
+                    let actual_planes =
+                        desc.planes.iter().take_while(|p| p.stride != 0).count() as u32;
+                    if actual_planes != expected_planes {
+                        return VmResponse::Err(SysError::new(EINVAL));
+                    }
+                }
                 // Determine size of buffer using 0 byte seek from end. This is preferred over
                 // `stride * height` as it's not limited to packed pixel formats.
                 let size = match fd.seek(SeekFrom::End(0)) {
@@ -276,11 +561,41 @@ impl VmRequest {
                     Err(e) => return VmResponse::Err(SysError::from(e)),
                 };
                 match register_memory(vm, sys_allocator, &fd, size as usize) {
+                    // `gpu_memory_allocator()` hands back a single fd backing every plane today, so
+                    // every plane maps to fds[0] until the allocator can split planes across
+                    // separate dma-bufs.
                     Ok((pfn, slot)) => VmResponse::AllocateAndRegisterGpuMemory {
-                        fd: MaybeOwnedFd::Owned(fd),
+                        fds: vec![MaybeOwnedFd::Owned(fd)],
                         pfn,
                         slot,
-                        desc },
+                        desc,
+                        modifier,
+                        plane_fd_index: [0, 0, 0],
+                    },
+                    Err(e) => VmResponse::Err(e),
+                }
+            }
+            &VmRequest::AttachDisk(ref fd, read_only) => {
+                match disk_hotplug.attach_disk(fd.as_raw_fd(), read_only) {
+                    Ok(slot) => VmResponse::AttachDisk { slot },
+                    Err(e) => VmResponse::Err(e),
+                }
+            }
+            &VmRequest::DetachDisk(slot) => {
+                match disk_hotplug.detach_disk(slot) {
+                    Ok(_) => VmResponse::Ok,
+                    Err(e) => VmResponse::Err(e),
+                }
+            }
+            &VmRequest::CpuAdd => {
+                match vcpu_hotplug.add_vcpu() {
+                    Ok(_) => VmResponse::Ok,
+                    Err(e) => VmResponse::Err(e),
+                }
+            }
+            &VmRequest::CpuRemove { cpu_id } => {
+                match vcpu_hotplug.remove_vcpu(cpu_id) {
+                    Ok(_) => VmResponse::Ok,
                     Err(e) => VmResponse::Err(e),
                 }
             }
@@ -292,6 +607,9 @@ impl VmRequest {
 ///
 /// Success is usually indicated `VmResponse::Ok` unless there is data associated with the response.
 pub enum VmResponse {
+    /// Reply to a `VmRequest::Hello`, advertising this end's protocol version and a bitmask of
+    /// `VM_REQUEST_TYPE_*` values it understands.
+    Hello { version: u32, supported_types: u64 },
     /// Indicates the request was executed successfully.
     Ok,
     /// Indicates the request encountered some error during execution.
@@ -301,18 +619,45 @@ pub enum VmResponse {
     RegisterMemory { pfn: u64, slot: u32 },
     /// The request to allocate and register GPU memory into guest address space was successfully
     /// done at page frame number `pfn` and memory slot number `slot` for buffer with `desc`.
-    AllocateAndRegisterGpuMemory { fd: MaybeOwnedFd, pfn: u64, slot: u32, desc: GpuMemoryDesc },
+    /// `modifier` is the DRM format modifier the request asked for, echoed back as is; the
+    /// allocator does not receive it, so it does not choose the buffer's layout. Multi-planar
+    /// formats may back different planes with different dma-buf FDs; `fds` holds every FD involved
+    /// and `plane_fd_index[i]` is the index into `fds` that `desc.planes[i]` lives in.
+    AllocateAndRegisterGpuMemory {
+        fds: Vec<MaybeOwnedFd>,
+        pfn: u64,
+        slot: u32,
+        desc: GpuMemoryDesc,
+        modifier: u64,
+        plane_fd_index: [u32; 3],
+    },
+    /// The request to attach a virtio-block device succeeded; it was assigned slot number `slot`.
+    AttachDisk { slot: u32 },
+    /// The balloon device's current size and virtio-balloon stats, in guest pages.
+    BalloonStats {
+        actual_pages: u64,
+        available_pages: u64,
+        free_pages: u64,
+        swap_in_pages: u64,
+        swap_out_pages: u64,
+    },
 }
 
 const VM_RESPONSE_TYPE_OK: u32 = 1;
 const VM_RESPONSE_TYPE_ERR: u32 = 2;
 const VM_RESPONSE_TYPE_REGISTER_MEMORY: u32 = 3;
 const VM_RESPONSE_TYPE_ALLOCATE_AND_REGISTER_GPU_MEMORY: u32 = 4;
-const VM_RESPONSE_SIZE: usize = 48;
+const VM_RESPONSE_TYPE_ATTACH_DISK: u32 = 5;
+const VM_RESPONSE_TYPE_BALLOON_STATS: u32 = 6;
+const VM_RESPONSE_TYPE_HELLO: u32 = 7;
+const VM_RESPONSE_SIZE: usize = 136;
 
 #[repr(C)]
 #[derive(Clone, Copy, Default)]
 struct VmResponseStruct {
+    request_id: Le64,
+    version: Le32,
+    _padding0: Le32,
     type_: Le32,
     errno: Le32,
     pfn: Le64,
@@ -323,25 +668,50 @@ struct VmResponseStruct {
     offset0: Le32,
     offset1: Le32,
     offset2: Le32,
+    _padding1: Le32,
+    actual_pages: Le64,
+    available_pages: Le64,
+    free_pages: Le64,
+    swap_in_pages: Le64,
+    swap_out_pages: Le64,
+    supported_types: Le64,
+    modifier: Le64,
+    num_fds: Le32,
+    fd_index0: Le32,
+    fd_index1: Le32,
+    fd_index2: Le32,
 }
 
 // Safe because it only has data and has no implicit padding.
 unsafe impl DataInit for VmResponseStruct {}
 
 impl VmResponse {
-    /// Receive a `VmResponse` from the given socket.
+    /// Receive a `VmResponse` from the given socket, along with the request id it was sent for.
     ///
     /// This should be called after the sending a `VmRequest` before sending another request.
-    pub fn recv(s: &UnixDatagram) -> VmControlResult<VmResponse> {
+    pub fn recv(s: &UnixDatagram) -> VmControlResult<(VmResponse, u64)> {
         let mut buf = [0; VM_RESPONSE_SIZE];
-        let (read, file) = s.recv_with_fd(&mut buf)
+        // Only `AllocateAndRegisterGpuMemory` can carry more than one fd (one per dma-buf backing
+        // a plane), so always ask for up to `MAX_GPU_MEMORY_FDS` rather than assuming a single fd.
+        let (read, files) = s.recv_with_fds(&mut buf, MAX_GPU_MEMORY_FDS)
             .map_err(|e| VmControlError::Recv(e))?;
         if read != VM_RESPONSE_SIZE {
             return Err(VmControlError::BadSize(read));
         }
         let resp: VmResponseStruct = buf.as_mut().get_ref(0).unwrap().load();
 
-        match resp.type_.into() {
+        if resp.version.to_native() != VM_CONTROL_PROTOCOL_VERSION {
+            return Err(VmControlError::VersionMismatch(resp.version.to_native()));
+        }
+        let request_id = resp.request_id.to_native();
+
+        let response = match resp.type_.into() {
+            VM_RESPONSE_TYPE_HELLO => {
+                Ok(VmResponse::Hello {
+                       version: resp.version.to_native(),
+                       supported_types: resp.supported_types.into(),
+                   })
+            }
             VM_RESPONSE_TYPE_OK => Ok(VmResponse::Ok),
             VM_RESPONSE_TYPE_ERR => {
                 Ok(VmResponse::Err(SysError::new(resp.errno.to_native() as i32)))
@@ -353,9 +723,16 @@ impl VmResponse {
                    })
             }
             VM_RESPONSE_TYPE_ALLOCATE_AND_REGISTER_GPU_MEMORY => {
-                let fd = file.ok_or(VmControlError::ExpectFd)?;
+                let num_fds = resp.num_fds.to_native() as usize;
+                if files.len() < num_fds {
+                    return Err(VmControlError::ExpectFd);
+                }
+                let fds = files.into_iter()
+                    .take(num_fds)
+                    .map(MaybeOwnedFd::Owned)
+                    .collect();
                 Ok(VmResponse::AllocateAndRegisterGpuMemory {
-                       fd: MaybeOwnedFd::Owned(fd),
+                       fds,
                        pfn: resp.pfn.into(),
                        slot: resp.slot.into(),
                        desc: GpuMemoryDesc {
@@ -366,21 +743,49 @@ impl VmResponse {
                                      GpuMemoryPlaneDesc { stride: resp.stride2.into(),
                                                           offset: resp.offset2.into() } ],
                        },
+                       modifier: resp.modifier.into(),
+                       plane_fd_index: [resp.fd_index0.into(), resp.fd_index1.into(),
+                                        resp.fd_index2.into()],
                   })
             }
+            VM_RESPONSE_TYPE_ATTACH_DISK => Ok(VmResponse::AttachDisk { slot: resp.slot.into() }),
+            VM_RESPONSE_TYPE_BALLOON_STATS => {
+                Ok(VmResponse::BalloonStats {
+                       actual_pages: resp.actual_pages.into(),
+                       available_pages: resp.available_pages.into(),
+                       free_pages: resp.free_pages.into(),
+                       swap_in_pages: resp.swap_in_pages.into(),
+                       swap_out_pages: resp.swap_out_pages.into(),
+                   })
+            }
             _ => Err(VmControlError::InvalidType),
-        }
+        }?;
+        Ok((response, request_id))
     }
 
-    /// Send a `VmResponse` over the given socket.
+    /// Send a `VmResponse` over the given socket, echoing the `request_id` of the `VmRequest` it
+    /// answers.
+    ///
+    /// `peer_capabilities`, if given, is a bitmask of the `VM_RESPONSE_TYPE_*` values the peer
+    /// accepts, keyed by the same bit positions as the type values. When present, a response type
+    /// absent from it is rejected with `VmControlError::UnsupportedRequest` rather than sent. Pass
+    /// `None` to send unconditionally; `VmRequest::Hello` carries no such bitmask.
     ///
     /// This must be called after receiving a `VmRequest` to indicate the outcome of that request's
     /// execution.
-    pub fn send(&self, s: &UnixDatagram) -> VmControlResult<()> {
+    pub fn send(&self, request_id: u64, peer_capabilities: Option<u64>,
+               s: &UnixDatagram) -> VmControlResult<()> {
         let mut resp = VmResponseStruct::default();
-        let mut fd_buf = [0; 1];
+        resp.request_id = Le64::from(request_id);
+        resp.version = Le32::from(VM_CONTROL_PROTOCOL_VERSION);
+        let mut fd_buf = [0; MAX_GPU_MEMORY_FDS];
         let mut fd_len = 0;
         match self {
+            &VmResponse::Hello { version, supported_types } => {
+                resp.type_ = Le32::from(VM_RESPONSE_TYPE_HELLO);
+                resp.version = Le32::from(version);
+                resp.supported_types = Le64::from(supported_types);
+            }
             &VmResponse::Ok => resp.type_ = Le32::from(VM_RESPONSE_TYPE_OK),
             &VmResponse::Err(e) => {
                 resp.type_ = Le32::from(VM_RESPONSE_TYPE_ERR);
@@ -391,9 +796,12 @@ impl VmResponse {
                 resp.pfn = Le64::from(pfn);
                 resp.slot = Le32::from(slot);
             }
-            &VmResponse::AllocateAndRegisterGpuMemory {ref fd, pfn, slot, desc } => {
-                fd_buf[0] = fd.as_raw_fd();
-                fd_len = 1;
+            &VmResponse::AllocateAndRegisterGpuMemory {ref fds, pfn, slot, desc, modifier,
+                                                        plane_fd_index } => {
+                for (i, fd) in fds.iter().enumerate() {
+                    fd_buf[i] = fd.as_raw_fd();
+                }
+                fd_len = fds.len();
                 resp.type_ = Le32::from(VM_RESPONSE_TYPE_ALLOCATE_AND_REGISTER_GPU_MEMORY);
                 resp.pfn = Le64::from(pfn);
                 resp.slot = Le32::from(slot);
@@ -403,6 +811,29 @@ impl VmResponse {
                 resp.offset0 = Le32::from(desc.planes[0].offset);
                 resp.offset1 = Le32::from(desc.planes[1].offset);
                 resp.offset2 = Le32::from(desc.planes[2].offset);
+                resp.modifier = Le64::from(modifier);
+                resp.num_fds = Le32::from(fds.len() as u32);
+                resp.fd_index0 = Le32::from(plane_fd_index[0]);
+                resp.fd_index1 = Le32::from(plane_fd_index[1]);
+                resp.fd_index2 = Le32::from(plane_fd_index[2]);
+            }
+            &VmResponse::AttachDisk { slot } => {
+                resp.type_ = Le32::from(VM_RESPONSE_TYPE_ATTACH_DISK);
+                resp.slot = Le32::from(slot);
+            }
+            &VmResponse::BalloonStats { actual_pages, available_pages, free_pages,
+                                        swap_in_pages, swap_out_pages } => {
+                resp.type_ = Le32::from(VM_RESPONSE_TYPE_BALLOON_STATS);
+                resp.actual_pages = Le64::from(actual_pages);
+                resp.available_pages = Le64::from(available_pages);
+                resp.free_pages = Le64::from(free_pages);
+                resp.swap_in_pages = Le64::from(swap_in_pages);
+                resp.swap_out_pages = Le64::from(swap_out_pages);
+            }
+        }
+        if let Some(supported) = peer_capabilities {
+            if supported & (1 << resp.type_.to_native()) == 0 {
+                return Err(VmControlError::UnsupportedRequest(resp.type_.to_native()));
             }
         }
         let mut buf = [0; VM_RESPONSE_SIZE];
@@ -426,13 +857,176 @@ mod tests {
     #[test]
     fn request_exit() {
         let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
-        VmRequest::Exit.send(&s1).unwrap();
+        VmRequest::Exit.send(1, None, &s1).unwrap();
+        match VmRequest::recv(&s2).unwrap() {
+            (VmRequest::Exit, request_id) => assert_eq!(request_id, 1),
+            _ => panic!("recv wrong request variant"),
+        }
+    }
+
+    #[test]
+    fn request_suspend() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        VmRequest::Suspend.send(1, None, &s1).unwrap();
+        match VmRequest::recv(&s2).unwrap() {
+            (VmRequest::Suspend, _) => {}
+            _ => panic!("recv wrong request variant"),
+        }
+    }
+
+    #[test]
+    fn request_resume() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        VmRequest::Resume.send(1, None, &s1).unwrap();
+        match VmRequest::recv(&s2).unwrap() {
+            (VmRequest::Resume, _) => {}
+            _ => panic!("recv wrong request variant"),
+        }
+    }
+
+    #[test]
+    fn request_balloon_stats() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        VmRequest::BalloonStats.send(1, None, &s1).unwrap();
         match VmRequest::recv(&s2).unwrap() {
-            VmRequest::Exit => {}
+            (VmRequest::BalloonStats, _) => {}
             _ => panic!("recv wrong request variant"),
         }
     }
 
+    #[test]
+    fn request_hello() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        VmRequest::Hello.send(1, None, &s1).unwrap();
+        match VmRequest::recv(&s2).unwrap() {
+            (VmRequest::Hello, _) => {}
+            _ => panic!("recv wrong request variant"),
+        }
+    }
+
+    #[test]
+    fn request_id_round_trip() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        VmRequest::Exit.send(0xdead_beef, None, &s1).unwrap();
+        let (_, request_id) = VmRequest::recv(&s2).unwrap();
+        assert_eq!(request_id, 0xdead_beef);
+    }
+
+    #[test]
+    fn request_version_mismatch() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let mut req = VmRequestStruct::default();
+        req.version = Le32::from(VM_CONTROL_PROTOCOL_VERSION + 1);
+        req.type_ = Le32::from(VM_REQUEST_TYPE_EXIT);
+        let mut buf = [0; VM_REQUEST_SIZE];
+        buf.as_mut().get_ref(0).unwrap().store(req);
+        s1.send_with_fds(buf.as_ref(), &[]).unwrap();
+        match VmRequest::recv(&s2) {
+            Err(VmControlError::VersionMismatch(v)) => {
+                assert_eq!(v, VM_CONTROL_PROTOCOL_VERSION + 1)
+            }
+            _ => panic!("recv wrong error variant"),
+        }
+    }
+
+    #[test]
+    fn resp_hello() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let r1 = VmResponse::Hello {
+            version: VM_CONTROL_PROTOCOL_VERSION,
+            supported_types: VM_REQUEST_TYPES_SUPPORTED,
+        };
+        r1.send(1, None, &s1).unwrap();
+        match VmResponse::recv(&s2).unwrap() {
+            (VmResponse::Hello { version, supported_types }, _) => {
+                assert_eq!(version, VM_CONTROL_PROTOCOL_VERSION);
+                assert_eq!(supported_types, VM_REQUEST_TYPES_SUPPORTED);
+            }
+            _ => panic!("recv wrong response variant"),
+        }
+    }
+
+    #[test]
+    fn resp_balloon_stats() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let r1 = VmResponse::BalloonStats {
+            actual_pages: 1234,
+            available_pages: 2345,
+            free_pages: 3456,
+            swap_in_pages: 4567,
+            swap_out_pages: 5678,
+        };
+        r1.send(1, None, &s1).unwrap();
+        match VmResponse::recv(&s2).unwrap() {
+            (VmResponse::BalloonStats { actual_pages, available_pages, free_pages, swap_in_pages,
+                                        swap_out_pages }, _) => {
+                assert_eq!(actual_pages, 1234);
+                assert_eq!(available_pages, 2345);
+                assert_eq!(free_pages, 3456);
+                assert_eq!(swap_in_pages, 4567);
+                assert_eq!(swap_out_pages, 5678);
+            }
+            _ => panic!("recv wrong response variant"),
+        }
+    }
+
+    #[test]
+    fn vcpu_control_pause_resume() {
+        let vcpu_control = VcpuControl::new();
+        assert!(!vcpu_control.is_paused());
+        vcpu_control.pause();
+        assert!(vcpu_control.is_paused());
+        vcpu_control.resume();
+        assert!(!vcpu_control.is_paused());
+    }
+
+    #[test]
+    fn vcpu_control_park_if_paused_blocks_until_resumed() {
+        use std::sync::mpsc;
+        use std::sync::Arc;
+        use std::thread;
+        use std::time::Duration;
+
+        let vcpu_control = Arc::new(VcpuControl::new());
+        vcpu_control.pause();
+
+        let (parked_tx, parked_rx) = mpsc::channel();
+        let (returned_tx, returned_rx) = mpsc::channel();
+        let vcpu_thread = {
+            let vcpu_control = vcpu_control.clone();
+            thread::spawn(move || {
+                parked_tx.send(()).unwrap();
+                vcpu_control.park_if_paused();
+                returned_tx.send(()).unwrap();
+            })
+        };
+
+        // Wait for the vCPU thread to actually call `park_if_paused` before asserting that it's
+        // still blocked there, so this test can't pass on a racing thread that hasn't parked yet.
+        parked_rx.recv().unwrap();
+        assert_eq!(
+            returned_rx.recv_timeout(Duration::from_millis(100)),
+            Err(mpsc::RecvTimeoutError::Timeout),
+            "park_if_paused must not return while the VM is paused"
+        );
+
+        vcpu_control.resume();
+        returned_rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("park_if_paused must return promptly once resumed");
+
+        vcpu_thread.join().unwrap();
+    }
+
+    #[test]
+    fn gpu_format_plane_count_known_formats() {
+        assert_eq!(gpu_format_plane_count(DRM_FORMAT_XRGB8888, 0), Some(1));
+        assert_eq!(gpu_format_plane_count(DRM_FORMAT_ARGB8888, 0), Some(1));
+        assert_eq!(gpu_format_plane_count(DRM_FORMAT_NV12, 0), Some(2));
+        assert_eq!(gpu_format_plane_count(DRM_FORMAT_YUV420, 0), Some(3));
+        assert_eq!(gpu_format_plane_count(drm_fourcc(b'A', b'B', b'1', b'2'), 0), None);
+    }
+
     #[test]
     fn request_register_memory() {
         if !kernel_has_memfd() { return; }
@@ -441,10 +1035,10 @@ mod tests {
         let mut shm = SharedMemory::new(None).unwrap();
         shm.set_size(shm_size as u64).unwrap();
         VmRequest::RegisterMemory(MaybeOwnedFd::Borrowed(shm.as_raw_fd()), shm_size)
-            .send(&s1)
+            .send(1, None, &s1)
             .unwrap();
         match VmRequest::recv(&s2).unwrap() {
-            VmRequest::RegisterMemory(MaybeOwnedFd::Owned(fd), size) => {
+            (VmRequest::RegisterMemory(MaybeOwnedFd::Owned(fd), size), _) => {
                 assert!(fd.as_raw_fd() >= 0);
                 assert_eq!(size, shm_size);
             }
@@ -455,19 +1049,81 @@ mod tests {
     #[test]
     fn request_unregister_memory() {
         let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
-        VmRequest::UnregisterMemory(77).send(&s1).unwrap();
+        VmRequest::UnregisterMemory(77).send(1, None, &s1).unwrap();
+        match VmRequest::recv(&s2).unwrap() {
+            (VmRequest::UnregisterMemory(slot), _) => assert_eq!(slot, 77),
+            _ => panic!("recv wrong request variant"),
+        }
+    }
+
+    #[test]
+    fn request_attach_disk() {
+        if !kernel_has_memfd() { return; }
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let mut shm = SharedMemory::new(None).unwrap();
+        shm.set_size(4096).unwrap();
+        VmRequest::AttachDisk(MaybeOwnedFd::Borrowed(shm.as_raw_fd()), true)
+            .send(1, None, &s1)
+            .unwrap();
         match VmRequest::recv(&s2).unwrap() {
-            VmRequest::UnregisterMemory(slot) => assert_eq!(slot, 77),
+            (VmRequest::AttachDisk(MaybeOwnedFd::Owned(fd), read_only), _) => {
+                assert!(fd.as_raw_fd() >= 0);
+                assert!(read_only);
+            }
             _ => panic!("recv wrong request variant"),
         }
     }
 
+    #[test]
+    fn request_detach_disk() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        VmRequest::DetachDisk(3).send(1, None, &s1).unwrap();
+        match VmRequest::recv(&s2).unwrap() {
+            (VmRequest::DetachDisk(slot), _) => assert_eq!(slot, 3),
+            _ => panic!("recv wrong request variant"),
+        }
+    }
+
+    #[test]
+    fn request_cpu_add() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        VmRequest::CpuAdd.send(1, None, &s1).unwrap();
+        match VmRequest::recv(&s2).unwrap() {
+            (VmRequest::CpuAdd, _) => {}
+            _ => panic!("recv wrong request variant"),
+        }
+    }
+
+    #[test]
+    fn request_cpu_remove() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        VmRequest::CpuRemove { cpu_id: 4 }.send(1, None, &s1).unwrap();
+        match VmRequest::recv(&s2).unwrap() {
+            (VmRequest::CpuRemove { cpu_id }, _) => assert_eq!(cpu_id, 4),
+            _ => panic!("recv wrong request variant"),
+        }
+    }
+
+    #[test]
+    fn resp_attach_disk() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let r1 = VmResponse::AttachDisk { slot: 9 };
+        r1.send(1, None, &s1).unwrap();
+        match VmResponse::recv(&s2).unwrap() {
+            (VmResponse::AttachDisk { slot }, _) => assert_eq!(slot, 9),
+            _ => panic!("recv wrong response variant"),
+        }
+    }
+
     #[test]
     fn request_expect_fd() {
         let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
-        let mut bad_request = [0; VM_REQUEST_SIZE];
-        bad_request[0] = VM_REQUEST_TYPE_REGISTER_MEMORY as u8;
-        s2.send_with_fds(bad_request.as_ref(), &[]).unwrap();
+        let mut req = VmRequestStruct::default();
+        req.version = Le32::from(VM_CONTROL_PROTOCOL_VERSION);
+        req.type_ = Le32::from(VM_REQUEST_TYPE_REGISTER_MEMORY);
+        let mut buf = [0; VM_REQUEST_SIZE];
+        buf.as_mut().get_ref(0).unwrap().store(req);
+        s2.send_with_fds(buf.as_ref(), &[]).unwrap();
         match VmRequest::recv(&s1) {
             Err(VmControlError::ExpectFd) => {}
             _ => panic!("recv wrong error variant"),
@@ -497,8 +1153,12 @@ mod tests {
     #[test]
     fn request_invalid_type() {
         let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
-        s2.send_with_fds([12; VM_REQUEST_SIZE].as_ref(), &[])
-            .unwrap();
+        let mut req = VmRequestStruct::default();
+        req.version = Le32::from(VM_CONTROL_PROTOCOL_VERSION);
+        req.type_ = Le32::from(0x0c0c_0c0c);
+        let mut buf = [0; VM_REQUEST_SIZE];
+        buf.as_mut().get_ref(0).unwrap().store(req);
+        s2.send_with_fds(buf.as_ref(), &[]).unwrap();
         match VmRequest::recv(&s1) {
             Err(VmControlError::InvalidType) => {}
             _ => panic!("recv wrong error variant"),
@@ -509,23 +1169,27 @@ mod tests {
     fn request_allocate_and_register_gpu_memory() {
         let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
         let gpu_width: u32 = 32;
-        let gpu_height: u32 = 32;
+        let gpu_height: u32 = 48;
         let gpu_format: u32 = 0x34325258;
+        let gpu_modifier: u64 = 0x0100_0000_0000_0001;
         let r = VmRequest::AllocateAndRegisterGpuMemory {
             width: gpu_width,
             height: gpu_height,
             format: gpu_format,
+            modifier: gpu_modifier,
         };
-        r.send(&s1).unwrap();
+        r.send(1, None, &s1).unwrap();
         match VmRequest::recv(&s2).unwrap() {
-            VmRequest::AllocateAndRegisterGpuMemory {
+            (VmRequest::AllocateAndRegisterGpuMemory {
                 width,
                 height,
                 format,
-            } => {
+                modifier,
+            }, _) => {
                 assert_eq!(width, gpu_width);
-                assert_eq!(height, gpu_width);
+                assert_eq!(height, gpu_height);
                 assert_eq!(format, gpu_format);
+                assert_eq!(modifier, gpu_modifier);
             }
             _ => panic!("recv wrong request variant"),
         }
@@ -534,9 +1198,9 @@ mod tests {
     #[test]
     fn resp_ok() {
         let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
-        VmResponse::Ok.send(&s1).unwrap();
+        VmResponse::Ok.send(1, None, &s1).unwrap();
         match VmResponse::recv(&s2).unwrap() {
-            VmResponse::Ok => {}
+            (VmResponse::Ok, _) => {}
             _ => panic!("recv wrong response variant"),
         }
     }
@@ -545,9 +1209,9 @@ mod tests {
     fn resp_err() {
         let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
         let r1 = VmResponse::Err(SysError::new(libc::EDESTADDRREQ));
-        r1.send(&s1).unwrap();
+        r1.send(1, None, &s1).unwrap();
         match VmResponse::recv(&s2).unwrap() {
-            VmResponse::Err(e) => {
+            (VmResponse::Err(e), _) => {
                 assert_eq!(e, SysError::new(libc::EDESTADDRREQ));
             }
             _ => panic!("recv wrong response variant"),
@@ -563,9 +1227,9 @@ mod tests {
             pfn: memory_pfn,
             slot: memory_slot,
         };
-        r1.send(&s1).unwrap();
+        r1.send(1, None, &s1).unwrap();
         match VmResponse::recv(&s2).unwrap() {
-            VmResponse::RegisterMemory { pfn, slot } => {
+            (VmResponse::RegisterMemory { pfn, slot }, _) => {
                 assert_eq!(pfn, memory_pfn);
                 assert_eq!(slot, memory_slot);
             }
@@ -600,8 +1264,12 @@ mod tests {
     #[test]
     fn resp_invalid_type() {
         let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
-        s2.send_with_fds([12; VM_RESPONSE_SIZE].as_ref(), &[])
-            .unwrap();
+        let mut resp = VmResponseStruct::default();
+        resp.version = Le32::from(VM_CONTROL_PROTOCOL_VERSION);
+        resp.type_ = Le32::from(0x0c0c_0c0c);
+        let mut buf = [0; VM_RESPONSE_SIZE];
+        buf.as_mut().get_ref(0).unwrap().store(resp);
+        s2.send_with_fds(buf.as_ref(), &[]).unwrap();
         match VmResponse::recv(&s1) {
             Err(e) => {
                 assert_eq!(e, VmControlError::InvalidType);
@@ -610,40 +1278,105 @@ mod tests {
         }
     }
 
+    #[test]
+    fn resp_version_mismatch() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let mut resp = VmResponseStruct::default();
+        resp.version = Le32::from(VM_CONTROL_PROTOCOL_VERSION + 1);
+        resp.type_ = Le32::from(VM_RESPONSE_TYPE_OK);
+        let mut buf = [0; VM_RESPONSE_SIZE];
+        buf.as_mut().get_ref(0).unwrap().store(resp);
+        s1.send_with_fds(buf.as_ref(), &[]).unwrap();
+        match VmResponse::recv(&s2) {
+            Err(VmControlError::VersionMismatch(v)) => {
+                assert_eq!(v, VM_CONTROL_PROTOCOL_VERSION + 1)
+            }
+            _ => panic!("recv wrong error variant"),
+        }
+    }
+
     #[test]
     fn resp_allocate_and_register_gpu_memory() {
         if !kernel_has_memfd() { return; }
         let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
         let shm_size: usize = 4096;
-        let mut shm = SharedMemory::new(None).unwrap();
-        shm.set_size(shm_size as u64).unwrap();
+        let mut shm0 = SharedMemory::new(None).unwrap();
+        shm0.set_size(shm_size as u64).unwrap();
+        let mut shm1 = SharedMemory::new(None).unwrap();
+        shm1.set_size(shm_size as u64).unwrap();
         let memory_pfn = 55;
         let memory_slot = 66;
+        let memory_modifier: u64 = 0x0100_0000_0000_0001;
         let memory_planes = [
             GpuMemoryPlaneDesc { stride: 32, offset: 84 },
             GpuMemoryPlaneDesc { stride: 48, offset: 96 },
             GpuMemoryPlaneDesc { stride: 64, offset: 112 }
         ];
+        // YUV420-like layout: the luma plane lives in the first dma-buf, the two chroma planes
+        // share the second.
+        let memory_plane_fd_index = [0, 1, 1];
         let r1 = VmResponse::AllocateAndRegisterGpuMemory {
-            fd: MaybeOwnedFd::Borrowed(shm.as_raw_fd()),
+            fds: vec![MaybeOwnedFd::Borrowed(shm0.as_raw_fd()),
+                      MaybeOwnedFd::Borrowed(shm1.as_raw_fd())],
             pfn: memory_pfn,
             slot: memory_slot,
             desc: GpuMemoryDesc { planes: memory_planes },
+            modifier: memory_modifier,
+            plane_fd_index: memory_plane_fd_index,
         };
-        r1.send(&s1).unwrap();
+        r1.send(1, None, &s1).unwrap();
         match VmResponse::recv(&s2).unwrap() {
-            VmResponse::AllocateAndRegisterGpuMemory {
-                fd,
+            (VmResponse::AllocateAndRegisterGpuMemory {
+                fds,
                 pfn,
                 slot,
                 desc,
-            } => {
-                assert!(fd.as_raw_fd() >= 0);
+                modifier,
+                plane_fd_index,
+            }, _) => {
+                assert_eq!(fds.len(), 2);
+                for fd in &fds {
+                    assert!(fd.as_raw_fd() >= 0);
+                }
                 assert_eq!(pfn, memory_pfn);
                 assert_eq!(slot, memory_slot);
                 assert_eq!(desc.planes, memory_planes);
+                assert_eq!(modifier, memory_modifier);
+                assert_eq!(plane_fd_index, memory_plane_fd_index);
             }
             _ => panic!("recv wrong response variant"),
         }
     }
+
+    #[test]
+    fn request_unsupported_by_peer_capabilities() {
+        let (s1, _s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let peer_capabilities = VM_REQUEST_TYPES_SUPPORTED & !(1 << VM_REQUEST_TYPE_CPU_ADD);
+        match VmRequest::CpuAdd.send(1, Some(peer_capabilities), &s1) {
+            Err(VmControlError::UnsupportedRequest(t)) => assert_eq!(t, VM_REQUEST_TYPE_CPU_ADD),
+            _ => panic!("send should have rejected an unsupported request"),
+        }
+    }
+
+    #[test]
+    fn request_supported_by_peer_capabilities() {
+        let (s1, s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        VmRequest::CpuAdd
+            .send(1, Some(VM_REQUEST_TYPES_SUPPORTED), &s1)
+            .unwrap();
+        match VmRequest::recv(&s2).unwrap() {
+            (VmRequest::CpuAdd, _) => {}
+            _ => panic!("recv wrong request variant"),
+        }
+    }
+
+    #[test]
+    fn resp_unsupported_by_peer_capabilities() {
+        let (s1, _s2) = UnixDatagram::pair().expect("failed to create socket pair");
+        let peer_capabilities = !(1u64 << VM_RESPONSE_TYPE_OK);
+        match VmResponse::Ok.send(1, Some(peer_capabilities), &s1) {
+            Err(VmControlError::UnsupportedRequest(t)) => assert_eq!(t, VM_RESPONSE_TYPE_OK),
+            _ => panic!("send should have rejected an unsupported response"),
+        }
+    }
 }